@@ -0,0 +1,221 @@
+use js_sys::{Array, Reflect};
+use wasm_bindgen::JsValue;
+
+use crate::{
+    components::{BehaviorTreeState, CharacterId, H1emuEntity, Position},
+    systems::{ATTACK_RANGE, distance_2d, go_to_args},
+};
+
+/// Time between two hits of the apply_damage action
+const ATTACK_INTERVAL: i64 = 1_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Success,
+    Failure,
+    /// Not done yet, the tree is ticked again from the root next frame
+    Running,
+}
+impl From<bool> for Status {
+    fn from(success: bool) -> Self {
+        if success {
+            Status::Success
+        } else {
+            Status::Failure
+        }
+    }
+}
+
+pub enum Condition {
+    TargetInRange(f32),
+    Hungry,
+    Random(f64),
+}
+
+pub enum Action {
+    /// Running until the target is within attack range
+    GoToTarget,
+    PlayAnimation(String),
+    /// Running while the previous hit is recovering
+    ApplyDamage,
+}
+
+pub enum Node {
+    Sequence(Vec<Node>),
+    Selector(Vec<Node>),
+    Inverter(Box<Node>),
+    Succeeder(Box<Node>),
+    /// Fails while the child ran successfully less than `cooldown` ms ago
+    Cooldown {
+        id: usize,
+        cooldown: i64,
+        child: Box<Node>,
+    },
+    Condition(Condition),
+    Action(Action),
+}
+
+/// What a tree can see and act on for the entity being evaluated
+pub struct TickContext<'a> {
+    pub h1emu_entity: &'a H1emuEntity,
+    pub position: &'a Position,
+    pub hungry: bool,
+    pub target: Option<(&'a Position, &'a CharacterId)>,
+    pub state: &'a mut BehaviorTreeState,
+    pub current_time: i64,
+}
+
+impl Node {
+    /// Builds a tree from its data definition, e.g.
+    /// `{ type: "sequence", children: [{ type: "condition", name: "target_in_range", range: 1.5 },
+    /// { type: "action", name: "apply_damage" }] }`
+    pub fn parse(definition: &JsValue) -> Result<Node, JsValue> {
+        let mut next_id = 0;
+        Node::parse_node(definition, &mut next_id)
+    }
+    fn parse_node(definition: &JsValue, next_id: &mut usize) -> Result<Node, JsValue> {
+        let node_type = get_string(definition, "type")?;
+        let node = match node_type.as_str() {
+            "sequence" => Node::Sequence(Node::parse_children(definition, next_id)?),
+            "selector" => Node::Selector(Node::parse_children(definition, next_id)?),
+            "inverter" => Node::Inverter(Box::new(Node::parse_child(definition, next_id)?)),
+            "succeeder" => Node::Succeeder(Box::new(Node::parse_child(definition, next_id)?)),
+            "cooldown" => {
+                let id = *next_id;
+                *next_id += 1;
+                Node::Cooldown {
+                    id,
+                    cooldown: get_number(definition, "ms")? as i64,
+                    child: Box::new(Node::parse_child(definition, next_id)?),
+                }
+            }
+            "condition" => Node::Condition(match get_string(definition, "name")?.as_str() {
+                "target_in_range" => {
+                    Condition::TargetInRange(get_number(definition, "range")? as f32)
+                }
+                "hungry" => Condition::Hungry,
+                "random" => Condition::Random(get_number(definition, "chance")?),
+                name => return Err(format!("unknown condition {}", name).into()),
+            }),
+            "action" => Node::Action(match get_string(definition, "name")?.as_str() {
+                "go_to_target" => Action::GoToTarget,
+                "play_animation" => Action::PlayAnimation(get_string(definition, "animation")?),
+                "apply_damage" => Action::ApplyDamage,
+                name => return Err(format!("unknown action {}", name).into()),
+            }),
+            node_type => return Err(format!("unknown node type {}", node_type).into()),
+        };
+        Ok(node)
+    }
+    fn parse_children(definition: &JsValue, next_id: &mut usize) -> Result<Vec<Node>, JsValue> {
+        let children = Reflect::get(definition, &JsValue::from_str("children"))?;
+        if !Array::is_array(&children) {
+            return Err("children must be an array".into());
+        }
+        Array::from(&children)
+            .iter()
+            .map(|child| Node::parse_node(&child, next_id))
+            .collect()
+    }
+    fn parse_child(definition: &JsValue, next_id: &mut usize) -> Result<Node, JsValue> {
+        let child = Reflect::get(definition, &JsValue::from_str("child"))?;
+        Node::parse_node(&child, next_id)
+    }
+
+    pub fn tick(&self, ctx: &mut TickContext) -> Status {
+        match self {
+            Node::Sequence(children) => {
+                for child in children {
+                    match child.tick(ctx) {
+                        Status::Success => continue,
+                        status => return status,
+                    }
+                }
+                Status::Success
+            }
+            Node::Selector(children) => {
+                for child in children {
+                    match child.tick(ctx) {
+                        Status::Failure => continue,
+                        status => return status,
+                    }
+                }
+                Status::Failure
+            }
+            Node::Inverter(child) => match child.tick(ctx) {
+                Status::Success => Status::Failure,
+                Status::Failure => Status::Success,
+                Status::Running => Status::Running,
+            },
+            Node::Succeeder(child) => match child.tick(ctx) {
+                Status::Running => Status::Running,
+                _ => Status::Success,
+            },
+            Node::Cooldown {
+                id,
+                cooldown,
+                child,
+            } => {
+                let ready_at = ctx.state.cooldowns.get(id).copied().unwrap_or_default();
+                if ctx.current_time < ready_at {
+                    return Status::Failure;
+                }
+                let status = child.tick(ctx);
+                if status == Status::Success {
+                    ctx.state.cooldowns.insert(*id, ctx.current_time + cooldown);
+                }
+                status
+            }
+            Node::Condition(condition) => match condition {
+                Condition::TargetInRange(range) => ctx
+                    .target
+                    .is_some_and(|(target_pos, _)| distance_2d(ctx.position, target_pos) <= *range),
+                Condition::Hungry => ctx.hungry,
+                Condition::Random(chance) => js_sys::Math::random() < *chance,
+            }
+            .into(),
+            Node::Action(action) => match action {
+                Action::GoToTarget => {
+                    let Some((target_pos, _)) = ctx.target else {
+                        return Status::Failure;
+                    };
+                    if distance_2d(ctx.position, target_pos) <= ATTACK_RANGE {
+                        return Status::Success;
+                    }
+                    ctx.h1emu_entity.go_to(&go_to_args(target_pos));
+                    Status::Running
+                }
+                Action::PlayAnimation(animation) => {
+                    let args = Array::new();
+                    args.push(&JsValue::from_str(animation));
+                    ctx.h1emu_entity.play_animation(&args);
+                    Status::Success
+                }
+                Action::ApplyDamage => {
+                    let Some((_, character_id)) = ctx.target else {
+                        return Status::Failure;
+                    };
+                    if ctx.current_time < ctx.state.next_attack {
+                        return Status::Running;
+                    }
+                    let args = Array::new();
+                    args.push(&JsValue::from_str(&character_id.0));
+                    ctx.h1emu_entity.apply_damage(&args);
+                    ctx.state.next_attack = ctx.current_time + ATTACK_INTERVAL;
+                    Status::Success
+                }
+            },
+        }
+    }
+}
+
+fn get_string(definition: &JsValue, key: &str) -> Result<String, JsValue> {
+    Reflect::get(definition, &JsValue::from_str(key))?
+        .as_string()
+        .ok_or_else(|| format!("{} must be a string", key).into())
+}
+fn get_number(definition: &JsValue, key: &str) -> Result<f64, JsValue> {
+    Reflect::get(definition, &JsValue::from_str(key))?
+        .as_f64()
+        .ok_or_else(|| format!("{} must be a number", key).into())
+}
@@ -1,67 +1,225 @@
-use std::sync::{
-    Arc,
-    atomic::{AtomicPtr, Ordering},
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicPtr, AtomicU32, Ordering},
+    },
 };
 
-use bevy_ecs::prelude::*;
+use bevy_ecs::{
+    component::{HookContext, Mutable},
+    prelude::*,
+    world::DeferredWorld,
+};
 use chrono::Utc;
 use js_sys::{Array, Float32Array, Function, Object, Reflect};
-use once_cell::unsync::Lazy;
 use wasm_bindgen::JsValue;
 
-use crate::log;
+use crate::{
+    AiStateKind, EntityType, error, log,
+    ressources::{CharacterIndex, GroupState, HerdState, PackState},
+};
 
+/// Names the host entity class uses for the methods and properties the AI relies on
+#[derive(Clone, Debug)]
 pub struct Bindings {
-    pub go_to: &'static str,
-    pub apply_damage: &'static str,
-    pub play_animation: &'static str,
-    pub detonate: &'static str,
-    pub destroy: &'static str,
-}
-const BINDINGS: Bindings = Bindings {
-    go_to: "goTo",
-    apply_damage: "applyDamage",
-    play_animation: "playAnimation",
-    detonate: "detonate",
-    destroy: "destroy",
-};
+    pub go_to: String,
+    pub apply_damage: String,
+    pub play_animation: String,
+    pub detonate: String,
+    pub destroy: String,
+    pub position: Vec<String>,
+    pub rotation: Vec<String>,
+    pub is_alive: Vec<String>,
+    pub character_id: Vec<String>,
+}
+impl Default for Bindings {
+    fn default() -> Self {
+        Bindings {
+            go_to: "goTo".to_string(),
+            apply_damage: "applyDamage".to_string(),
+            play_animation: "playAnimation".to_string(),
+            detonate: "detonate".to_string(),
+            destroy: "destroy".to_string(),
+            position: vec!["state".to_string(), "position".to_string()],
+            rotation: vec!["state".to_string(), "rotation".to_string()],
+            is_alive: vec!["isAlive".to_string()],
+            character_id: vec!["characterId".to_string()],
+        }
+    }
+}
+impl Bindings {
+    /// Overrides the defaults from a `{ goTo: "moveTo", position: "state.position" }` map
+    pub fn parse(value: &JsValue) -> Result<Self, JsValue> {
+        let mut bindings = Bindings::default();
+        let read = |key: &str| -> Result<Option<String>, JsValue> {
+            let value = Reflect::get(value, &JsValue::from_str(key))?;
+            if value.is_undefined() {
+                return Ok(None);
+            }
+            value
+                .as_string()
+                .map(Some)
+                .ok_or_else(|| JsValue::from_str(&format!("binding {key} must be a string")))
+        };
+        for (key, method) in [
+            ("goTo", &mut bindings.go_to),
+            ("applyDamage", &mut bindings.apply_damage),
+            ("playAnimation", &mut bindings.play_animation),
+            ("detonate", &mut bindings.detonate),
+            ("destroy", &mut bindings.destroy),
+        ] {
+            if let Some(name) = read(key)? {
+                *method = name;
+            }
+        }
+        for (key, property) in [
+            ("position", &mut bindings.position),
+            ("rotation", &mut bindings.rotation),
+            ("isAlive", &mut bindings.is_alive),
+            ("characterId", &mut bindings.character_id),
+        ] {
+            if let Some(path) = read(key)? {
+                *property = path.split('.').map(str::to_string).collect();
+            }
+        }
+        Ok(bindings)
+    }
+    /// Methods the systems call on entities of that archetype, `destroy` is only called
+    /// on consumed carcasses when those get despawned
+    pub fn required_methods(&self, entity_type: EntityType, despawn_consumed: bool) -> Vec<&str> {
+        let mut methods: Vec<&str> = match entity_type {
+            EntityType::Player => return vec![],
+            EntityType::Deer => vec![&self.go_to, &self.play_animation],
+            _ => vec![&self.go_to, &self.apply_damage, &self.play_animation],
+        };
+        if despawn_consumed {
+            methods.push(&self.destroy);
+        }
+        methods
+    }
+}
 
-thread_local! {
-    static IS_ALIVE_KEY: Lazy<JsValue> = Lazy::new(|| JsValue::from_str("isAlive"));
-    static POSITION_KEY: Lazy<JsValue> = Lazy::new(|| JsValue::from_str("position"));
-    static STATE_KEY: Lazy<JsValue> = Lazy::new(|| JsValue::from_str("state"));
-    static CHARACTERID_KEY: Lazy<JsValue> = Lazy::new(|| JsValue::from_str("characterId"));
-    static DESTROY_KEY: Lazy<JsValue> = Lazy::new(|| JsValue::from_str("destroy"));
+#[derive(Clone, Copy, Debug)]
+pub enum Method {
+    GoTo,
+    ApplyDamage,
+    PlayAnimation,
+    Detonate,
+    Destroy,
+}
+const METHOD_COUNT: usize = 5;
+impl Method {
+    fn name(self, bindings: &Bindings) -> &str {
+        match self {
+            Method::GoTo => &bindings.go_to,
+            Method::ApplyDamage => &bindings.apply_damage,
+            Method::PlayAnimation => &bindings.play_animation,
+            Method::Detonate => &bindings.detonate,
+            Method::Destroy => &bindings.destroy,
+        }
+    }
 }
-#[derive(Component, Default)]
-pub struct H1emuEntity(pub Arc<AtomicPtr<js_sys::Object>>);
-impl H1emuEntity {
-    pub fn get_object(&self) -> Result<&Object, ()> {
-        // Load the raw pointer
-        let ptr = self.0.load(Ordering::Acquire);
 
-        // Check if the pointer is null
-        if !ptr.is_null() {
-            // Convert the raw pointer to a reference
-            unsafe {
-                let obj = &*ptr;
-
-                // Ensure the conversion is valid
-                if obj.is_object() {
-                    Ok(obj)
-                } else {
-                    log!("The stored value is not an object.");
-                    Err(())
-                }
+/// Shared by the method caches of every entity of a world
+#[derive(Default)]
+pub struct MethodCacheCounters {
+    pub hits: AtomicU32,
+    pub misses: AtomicU32,
+}
+
+/// Function handles resolved on first call. The host object of an entity never changes and
+/// its methods are assumed not to be reassigned, AiManager::invalidate_methods swaps in a
+/// fresh cache when the host does patch one
+pub struct MethodCache {
+    functions: [AtomicPtr<Function>; METHOD_COUNT],
+    counters: Arc<MethodCacheCounters>,
+}
+impl MethodCache {
+    pub fn new(counters: Arc<MethodCacheCounters>) -> Self {
+        MethodCache {
+            functions: Default::default(),
+            counters,
+        }
+    }
+    pub fn get(&self, obj: &Object, method: Method, bindings: &Bindings) -> Option<&Function> {
+        let slot = &self.functions[method as usize];
+        let cached = slot.load(Ordering::Acquire);
+        if !cached.is_null() {
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            // only freed when the cache itself is dropped
+            return Some(unsafe { &*cached });
+        }
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        let name = method.name(bindings);
+        let func = match Reflect::get(obj, &JsValue::from_str(name)) {
+            Ok(value) if value.is_function() => Function::from(value),
+            Ok(_) => {
+                log!(format!("specified method {name} doesn't exist"));
+                return None;
             }
-        } else {
+            Err(err) => {
+                log!("reflected value doesn't exist");
+                error!(format!("{:?}", err));
+                return None;
+            }
+        };
+        let func = Box::into_raw(Box::new(func));
+        slot.store(func, Ordering::Release);
+        Some(unsafe { &*func })
+    }
+}
+impl Drop for MethodCache {
+    fn drop(&mut self) {
+        for slot in &self.functions {
+            let func = slot.swap(std::ptr::null_mut(), Ordering::AcqRel);
+            if !func.is_null() {
+                drop(unsafe { Box::from_raw(func) });
+            }
+        }
+    }
+}
+
+/// Owns the host object, the JS reference is released when the entity is despawned.
+/// `live_handles` is the counter of the world it belongs to
+pub struct HostObject(AtomicPtr<Object>, Arc<AtomicU32>);
+impl HostObject {
+    pub fn new(obj: Object, live_handles: Arc<AtomicU32>) -> Self {
+        live_handles.fetch_add(1, Ordering::Relaxed);
+        HostObject(AtomicPtr::new(Box::into_raw(Box::new(obj))), live_handles)
+    }
+    fn get(&self) -> Option<&Object> {
+        let ptr = self.0.load(Ordering::Acquire);
+        // the box lives as long as self and is only freed in drop
+        (!ptr.is_null()).then(|| unsafe { &*ptr })
+    }
+}
+impl Drop for HostObject {
+    fn drop(&mut self) {
+        let ptr = self.0.swap(std::ptr::null_mut(), Ordering::AcqRel);
+        if !ptr.is_null() {
+            drop(unsafe { Box::from_raw(ptr) });
+            self.1.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct H1emuEntity(pub Arc<HostObject>, pub Arc<Bindings>, pub Arc<MethodCache>);
+impl H1emuEntity {
+    pub fn get_object(&self) -> Result<&Object, ()> {
+        let Some(obj) = self.0.get() else {
             panic!("Null pointer encountered.");
+        };
+        if obj.is_object() {
+            Ok(obj)
+        } else {
+            log!("The stored value is not an object.");
+            Err(())
         }
     }
     pub fn get_position(&self) -> Position {
-        let position_js_value = STATE_KEY
-            .with(|state_key| POSITION_KEY.with(|pos_key| self.get_property(&[state_key, pos_key])))
-            .unwrap();
+        let position_js_value = self.get_property(&self.1.position).unwrap();
         let float32_array = Float32Array::from(position_js_value);
 
         let x = float32_array.get_index(0);
@@ -70,23 +228,45 @@ impl H1emuEntity {
 
         Position { x, y, z }
     }
+    pub fn get_orientation(&self) -> Option<Orientation> {
+        let rotation_js_value = self.get_property(&self.1.rotation).ok()?;
+        Orientation::from_rotation(&Float32Array::from(rotation_js_value).to_vec())
+    }
+    #[allow(non_snake_case)]
     pub fn get_characterId(&self) -> JsValue {
-        CHARACTERID_KEY
-            .with(|key| self.get_property(&[key]))
-            .unwrap()
+        self.get_property(&self.1.character_id).unwrap()
     }
+    #[allow(non_snake_case)]
     pub fn get_isAlive(&self) -> bool {
-        let js_value = IS_ALIVE_KEY.with(|key| self.get_property(&[key])).unwrap();
+        let js_value = self.get_property(&self.1.is_alive).unwrap();
         js_value.is_truthy()
     }
-    fn get_property(&self, property_chain: &[&JsValue]) -> Result<JsValue, ()> {
+    /// Checks the given properties and methods exist before any system relies on them
+    pub fn validate(&self, properties: &[&[String]], methods: &[&str]) -> Result<(), String> {
+        for property in properties {
+            if self.get_property(property).is_err() {
+                return Err(format!("missing property {}", property.join(".")));
+            }
+        }
+        let obj = self.get_object().map_err(|_| "not an object".to_string())?;
+        for method in methods {
+            let is_function = Reflect::get(obj, &JsValue::from_str(method))
+                .map(|value| value.is_function())
+                .unwrap_or(false);
+            if !is_function {
+                return Err(format!("missing method {method}"));
+            }
+        }
+        Ok(())
+    }
+    fn get_property(&self, property_chain: &[String]) -> Result<JsValue, ()> {
         let mut current = match self.get_object() {
             Ok(obj) => JsValue::from(obj),
             _ => return Err(()),
         };
 
-        for &prop in property_chain {
-            let next = Reflect::get(&current, prop).map_err(|_| ())?;
+        for prop in property_chain {
+            let next = Reflect::get(&current, &JsValue::from_str(prop)).map_err(|_| ())?;
 
             if next.is_undefined() {
                 return Err(());
@@ -98,40 +278,54 @@ impl H1emuEntity {
         Ok(current)
     }
     pub fn play_animation(&self, args: &Array) {
-        let method = &JsValue::from_str(BINDINGS.play_animation);
-        self.call_method(method, args);
+        self.call_method(Method::PlayAnimation, args);
     }
     pub fn detonate(&self, args: &Array) {
-        let method = &JsValue::from_str(BINDINGS.detonate);
-        self.call_method(method, args);
+        self.call_method(Method::Detonate, args);
     }
     pub fn destroy(&self) {
         let args = js_sys::Array::new();
-        DESTROY_KEY.with(|key| self.call_method(key, &args))
+        self.call_method(Method::Destroy, &args);
     }
     pub fn go_to(&self, args: &Array) {
-        let method = &JsValue::from_str(BINDINGS.go_to);
-        self.call_method(method, args);
+        self.call_method(Method::GoTo, args);
     }
     pub fn apply_damage(&self, args: &Array) {
-        let method = &JsValue::from_str(BINDINGS.apply_damage);
-        self.call_method(method, args);
+        self.call_method(Method::ApplyDamage, args);
     }
-    fn call_method(&self, method: &JsValue, args: &Array) {
+    /// Milliseconds spent resolving `method` `iterations` times by name, as every call did
+    /// before the cache, then through a fresh cache
+    pub fn time_method_lookup(
+        &self,
+        method: Method,
+        iterations: u32,
+    ) -> Result<(f64, f64), String> {
+        let obj = self.get_object().map_err(|_| "not an object".to_string())?;
+        let name = method.name(&self.1);
+        let start = js_sys::Date::now();
+        for _ in 0..iterations {
+            let value =
+                Reflect::get(obj, &JsValue::from_str(name)).map_err(|err| format!("{err:?}"))?;
+            if !value.is_function() {
+                return Err(format!("missing method {name}"));
+            }
+            std::hint::black_box(Function::from(value));
+        }
+        let uncached = js_sys::Date::now() - start;
+        let cache = MethodCache::new(Default::default());
+        let start = js_sys::Date::now();
+        for _ in 0..iterations {
+            std::hint::black_box(cache.get(obj, method, &self.1));
+        }
+        let cached = js_sys::Date::now() - start;
+        Ok((uncached, cached))
+    }
+    fn call_method(&self, method: Method, args: &Array) {
         if let Ok(obj) = self.get_object() {
-            if let Ok(reflect_value) = Reflect::get(obj, method) {
-                let func: Function = Function::from(reflect_value);
-                if func.is_function() {
-                    let result = func.apply(obj, args);
-                    if result.is_err() {
-                        log!(format!("{:?}", result.unwrap_err()));
-                    }
-                } else {
-                    log!("specified method doesn't exist");
-                }
-            } else {
-                log!("reflected value doesn't exist");
-                log!(format!("{:?}", Reflect::get(obj, method).unwrap_err()));
+            if let Some(func) = self.2.get(obj, method, &self.1)
+                && let Err(err) = func.apply(obj, args)
+            {
+                error!(format!("{:?}", err));
             }
         } else {
             log!("Object doesn't exist");
@@ -146,10 +340,108 @@ pub struct Position {
     pub z: f32,
 }
 
-#[derive(Component)]
-pub struct HostileToPlayer();
+/// Yaw in radians, 0 faces +z
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Orientation(pub f32);
+impl Orientation {
+    /// Accepts a [x, y, z, w] quaternion or a single [yaw], anything else is rejected
+    pub fn from_rotation(rotation: &[f32]) -> Option<Self> {
+        match rotation {
+            [x, y, z, w] => Some(Orientation(
+                (2.0 * (w * y + x * z)).atan2(1.0 - 2.0 * (y * y + x * x)),
+            )),
+            [yaw] => Some(Orientation(*yaw)),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Component)]
 pub struct Coward();
+/// Faction id the relationship matrix is looked up with
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Faction(pub u32);
+
+const THREAT_MEMORY: i64 = 5_000;
+const THREAT_DAMAGE_WEIGHT: f32 = 0.01;
+/// A new target needs a quarter more threat than the current one to take over
+const THREAT_SWITCH_RATIO: f32 = 0.25;
+/// Scores closer to 0 than that count as this so the margin never vanishes or flips sign
+const THREAT_SWITCH_FLOOR: f32 = 4.0;
+
+#[derive(Default)]
+pub struct Threat {
+    pub damage: f32,
+    pub noise: f32,
+    /// Last time it was seen, heard or felt
+    pub last_seen: i64,
+}
+#[derive(Component, Default)]
+pub struct ThreatTable {
+    pub entries: HashMap<Entity, Threat>,
+    pub current: Option<Entity>,
+}
+impl ThreatTable {
+    pub fn seen(&mut self, target: Entity, current_time: i64) -> &mut Threat {
+        let threat = self.entries.entry(target).or_default();
+        threat.last_seen = current_time;
+        threat
+    }
+    pub fn add_damage(&mut self, target: Entity, amount: f32, current_time: i64) {
+        self.seen(target, current_time).damage += amount;
+    }
+    pub fn add_noise(&mut self, target: Entity, loudness: f32, current_time: i64) {
+        self.seen(target, current_time).noise += loudness;
+    }
+    /// Picks the highest threat, `distance_of` returns None for targets that are gone
+    pub fn select(
+        &mut self,
+        current_time: i64,
+        range: f32,
+        distance_of: impl Fn(Entity) -> Option<f32>,
+    ) -> Option<Entity> {
+        let mut scores: Vec<(Entity, f32)> = Vec::with_capacity(self.entries.len());
+        self.entries.retain(|target, threat| {
+            if current_time - threat.last_seen > THREAT_MEMORY {
+                return false;
+            }
+            let Some(distance) = distance_of(*target) else {
+                return false;
+            };
+            let seconds_unseen = (current_time - threat.last_seen) as f32 / 1000.0;
+            let score =
+                (range - distance).max(0.0) + threat.damage * THREAT_DAMAGE_WEIGHT + threat.noise
+                    - seconds_unseen;
+            scores.push((*target, score));
+            true
+        });
+        let best = scores
+            .iter()
+            .copied()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        let current = self
+            .current
+            .and_then(|current| scores.iter().find(|(target, _)| *target == current));
+        self.current = match (best, current) {
+            (Some((_, best_score)), Some((current, current_score)))
+                if best_score - current_score
+                    <= current_score.abs().max(THREAT_SWITCH_FLOOR) * THREAT_SWITCH_RATIO =>
+            {
+                Some(*current)
+            }
+            (best, _) => best.map(|(target, _)| target),
+        };
+        self.current
+    }
+}
+
+/// Per-entity memory of the behavior tree, cooldowns keyed by node id.
+/// Entities carrying it are driven by their archetype tree instead of the built-in systems
+#[derive(Component, Default)]
+pub struct BehaviorTreeState {
+    pub cooldowns: HashMap<usize, i64>,
+    pub next_attack: i64,
+}
 
 #[derive(Component)]
 pub struct IsAttacking {
@@ -158,16 +450,227 @@ pub struct IsAttacking {
 }
 
 #[derive(Component, Clone)]
+#[component(on_insert = index_character_id, on_replace = unindex_character_id)]
 pub struct CharacterId(pub String);
+fn index_character_id(mut world: DeferredWorld, context: HookContext) {
+    let Some(character_id) = world.get::<CharacterId>(context.entity).cloned() else {
+        return;
+    };
+    if let Some(mut index) = world.get_resource_mut::<CharacterIndex>() {
+        index.0.insert(character_id.0, context.entity);
+    }
+}
+fn unindex_character_id(mut world: DeferredWorld, context: HookContext) {
+    let Some(character_id) = world.get::<CharacterId>(context.entity).cloned() else {
+        return;
+    };
+    if let Some(mut index) = world.get_resource_mut::<CharacterIndex>()
+        && index.0.get(&character_id.0) == Some(&context.entity)
+    {
+        index.0.remove(&character_id.0);
+    }
+}
 
 #[derive(Component, Default)]
 pub struct Alive();
 #[derive(Component)]
 pub struct Dead();
 
+#[derive(Component, Clone, Copy)]
+pub struct Archetype(pub EntityType);
+
+/// Opt-in polling of the host `isAlive` property, used when the host doesn't push deaths
 #[derive(Component)]
+pub struct PollAliveness {
+    pub interval: i64,
+    pub next_check: i64,
+}
+impl PollAliveness {
+    pub fn new(interval: i64) -> Self {
+        PollAliveness {
+            interval,
+            next_check: Utc::now().timestamp_millis() + interval,
+        }
+    }
+    pub fn is_due(&self, current_time: i64) -> bool {
+        current_time >= self.next_check
+    }
+    pub fn delay(&mut self, current_time: i64) {
+        self.next_check = current_time + self.interval;
+    }
+}
+
+/// Holds a feeding slot of the corpse until removed, whether finished, interrupted,
+/// dead or despawned
+#[derive(Component)]
+#[component(on_remove = release_feeding_slot)]
 pub struct Eating {
     pub time: i64,
+    pub corpse: Entity,
+}
+fn release_feeding_slot(mut world: DeferredWorld, context: HookContext) {
+    let Some(corpse) = world
+        .get::<Eating>(context.entity)
+        .map(|eating| eating.corpse)
+    else {
+        return;
+    };
+    if let Some(mut carcass) = world.get_mut::<Carcass>(corpse) {
+        carcass.feeders = carcass.feeders.saturating_sub(1);
+    }
+}
+/// Food left on a dead body and how many carnivores are feeding on it
+#[derive(Component)]
+pub struct Carcass {
+    pub food: u8,
+    pub feeders: u8,
+}
+#[derive(Component)]
+pub struct Consumed();
+/// Last hit reported by the host
+#[derive(Component)]
+pub struct Damaged {
+    pub attacker: Option<Entity>,
+    pub amount: f32,
+    pub time: i64,
+}
+#[derive(Component)]
+pub struct HerdMember {
+    pub herd: u32,
+    /// Place around the leader, keeps followers apart
+    pub slot: u8,
+    pub next_order: i64,
+}
+/// Where a zombie of a horde goes around its target, only attack slots may swing
+#[derive(Component)]
+pub struct HordeSlot {
+    pub target: Entity,
+    pub destination: Position,
+    pub attack_slot: Option<usize>,
+}
+/// Going after an animal of a prey faction
+#[derive(Component)]
+pub struct Hunting {
+    pub prey: Entity,
+    pub next_order: i64,
+}
+#[derive(Component)]
+pub struct Chasing {
+    pub target: Entity,
+    pub next_order: i64,
+}
+#[derive(Component, Default)]
+pub struct Roaming {
+    pub next_move: i64,
+    pub wandering: bool,
+}
+#[derive(Component)]
+pub struct Returning {
+    pub destination: Position,
+    pub until: i64,
+}
+#[derive(Component)]
+pub struct AiState {
+    pub kind: AiStateKind,
+    pub since: i64,
+}
+impl AiState {
+    pub fn new(kind: AiStateKind) -> Self {
+        AiState {
+            kind,
+            since: Utc::now().timestamp_millis(),
+        }
+    }
+    pub fn time_in_state(&self, current_time: i64) -> i64 {
+        current_time - self.since
+    }
+    /// Applies the transition if the guard allows it, returns the previous state
+    pub fn transition(&mut self, to: AiStateKind, current_time: i64) -> Option<AiStateKind> {
+        if !self.kind.can_transition(to) {
+            return None;
+        }
+        let from = self.kind;
+        self.kind = to;
+        self.since = current_time;
+        Some(from)
+    }
+}
+#[derive(Component)]
+pub struct LastMeal(pub Position);
+#[derive(Component)]
+pub struct PackMember {
+    pub pack: u32,
+    pub slot: u8,
+}
+
+/// Member of a pack or a herd, its slot places it around the others
+pub trait GroupMember: Component<Mutability = Mutable> {
+    type State: GroupState;
+    fn new(group: u32, slot: u8) -> Self;
+    fn group(&self) -> u32;
+    fn slot(&self) -> u8;
+    fn set_slot(&mut self, slot: u8);
+}
+impl GroupMember for PackMember {
+    type State = PackState;
+    fn new(pack: u32, slot: u8) -> Self {
+        PackMember { pack, slot }
+    }
+    fn group(&self) -> u32 {
+        self.pack
+    }
+    fn slot(&self) -> u8 {
+        self.slot
+    }
+    fn set_slot(&mut self, slot: u8) {
+        self.slot = slot;
+    }
+}
+impl GroupMember for HerdMember {
+    type State = HerdState;
+    fn new(herd: u32, slot: u8) -> Self {
+        HerdMember {
+            herd,
+            slot,
+            next_order: 0,
+        }
+    }
+    fn group(&self) -> u32 {
+        self.herd
+    }
+    fn slot(&self) -> u8 {
+        self.slot
+    }
+    fn set_slot(&mut self, slot: u8) {
+        self.slot = slot;
+    }
+}
+#[derive(Component)]
+pub struct Flanking {
+    pub next_order: i64,
+}
+#[derive(Component)]
+pub struct Retreating {
+    pub until: i64,
+}
+#[derive(Component)]
+pub struct Territory {
+    pub home: Position,
+    pub radius: f32,
+    pub charge_radius: f32,
+}
+/// A player got warned for entering the territory
+#[derive(Component)]
+pub struct Warning {
+    pub target: Entity,
+    pub distance: f32,
+}
+#[derive(Component)]
+pub struct Charging {
+    pub target: Entity,
+    pub next_order: i64,
+    /// An enraged bear keeps chasing outside of its territory
+    pub enraged_until: i64,
 }
 #[derive(Component, Default)]
 pub struct HungerLevel(pub u8);
@@ -188,6 +691,54 @@ impl TrapsCooldown {
         current_time < self.last_trigger + self.cooldown
     }
 }
+#[derive(Component)]
+pub struct ScreamCooldown {
+    pub last_scream: i64,
+    pub cooldown: i64,
+}
+impl ScreamCooldown {
+    pub fn new(cooldown: i64) -> Self {
+        ScreamCooldown {
+            last_scream: 0,
+            cooldown,
+        }
+    }
+    pub fn is_in_cooldown(&self) -> bool {
+        let current_time = Utc::now().timestamp_millis();
+        current_time < self.last_scream + self.cooldown
+    }
+}
+/// Last known position of something worth checking out
+#[derive(Component)]
+pub struct Investigating {
+    pub position: Position,
+    pub until: i64,
+    pub ordered: bool,
+}
+impl Investigating {
+    pub fn new(position: Position, duration: i64) -> Self {
+        Investigating {
+            position,
+            until: Utc::now().timestamp_millis() + duration,
+            ordered: false,
+        }
+    }
+}
+#[derive(Component)]
+pub struct Fleeing {
+    pub from: Position,
+    pub until: i64,
+    pub ordered: bool,
+}
+impl Fleeing {
+    pub fn new(from: Position, duration: i64) -> Self {
+        Fleeing {
+            from,
+            until: Utc::now().timestamp_millis() + duration,
+            ordered: false,
+        }
+    }
+}
 #[derive(Component, Default)]
 pub struct DespawnCooldown {
     last_activity: i64,
@@ -206,6 +757,10 @@ impl DespawnCooldown {
         let current_time = Utc::now().timestamp_millis();
         current_time > self.last_activity + self.cooldown
     }
+    pub fn remaining(&self) -> i64 {
+        let current_time = Utc::now().timestamp_millis();
+        (self.last_activity + self.cooldown - current_time).max(0)
+    }
     pub fn register_activity(&mut self) {
         let current_time = Utc::now().timestamp_millis();
         self.last_activity = current_time;
@@ -229,8 +784,124 @@ pub struct EntityDefaultBundle {
     pub character_id: CharacterId,
     pub alive: Alive,
 }
-#[derive(Bundle, Default)]
+#[derive(Bundle)]
 pub struct DefaultBundle {
     pub h1emu_entity: H1emuEntity,
     pub position: Position,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RANGE: f32 = 20.0;
+
+    fn table(targets: &[(Entity, f32)], current: Option<Entity>) -> ThreatTable {
+        let mut table = ThreatTable {
+            current,
+            ..Default::default()
+        };
+        for &(target, noise) in targets {
+            table.add_noise(target, noise, 0);
+        }
+        table
+    }
+
+    /// Everyone stands at the edge of the range so only the threat entries score
+    fn at_range(_: Entity) -> Option<f32> {
+        Some(RANGE)
+    }
+
+    #[test]
+    fn select_picks_the_highest_threat() {
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut table = table(&[(a, 1.0), (b, 3.0)], None);
+        assert_eq!(table.select(0, RANGE, at_range), Some(b));
+    }
+
+    #[test]
+    fn select_keeps_the_current_target_within_the_margin() {
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut table = table(&[(a, 10.0), (b, 12.0)], Some(a));
+        assert_eq!(table.select(0, RANGE, at_range), Some(a));
+    }
+
+    #[test]
+    fn select_switches_past_the_margin() {
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut table = table(&[(a, 10.0), (b, 13.0)], Some(a));
+        assert_eq!(table.select(0, RANGE, at_range), Some(b));
+    }
+
+    #[test]
+    fn select_margin_holds_around_zero() {
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut table = table(&[(a, 0.0), (b, 0.5)], Some(a));
+        assert_eq!(table.select(0, RANGE, at_range), Some(a));
+        table.add_noise(b, 1.0, 0);
+        assert_eq!(table.select(0, RANGE, at_range), Some(b));
+    }
+
+    #[test]
+    fn select_margin_holds_for_negative_scores() {
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut table = table(&[(a, 0.0), (b, 0.0)], Some(a));
+        // unseen for 3s, a scores -3 and b -2.5
+        table.entries.get_mut(&b).unwrap().noise = 0.5;
+        assert_eq!(table.select(3_000, RANGE, at_range), Some(a));
+        table.entries.get_mut(&b).unwrap().noise = 2.0;
+        assert_eq!(table.select(3_000, RANGE, at_range), Some(b));
+    }
+
+    #[test]
+    fn select_forgets_gone_and_stale_targets() {
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut table = table(&[(a, 10.0), (b, 1.0)], Some(a));
+        let only_b = |target| (target == b).then_some(RANGE);
+        assert_eq!(table.select(0, RANGE, only_b), Some(b));
+        assert!(!table.entries.contains_key(&a));
+        assert_eq!(table.select(THREAT_MEMORY + 1, RANGE, at_range), None);
+        assert!(table.entries.is_empty());
+    }
+
+    #[test]
+    fn orientation_takes_a_yaw_or_a_quaternion() {
+        assert_eq!(Orientation::from_rotation(&[1.5]), Some(Orientation(1.5)));
+        assert_eq!(
+            Orientation::from_rotation(&[0.0, 0.0, 0.0, 1.0]),
+            Some(Orientation(0.0))
+        );
+        for yaw in [0.5, -2.0, std::f32::consts::FRAC_PI_2] {
+            let (sin, cos) = (yaw / 2.0).sin_cos();
+            let Some(Orientation(converted)) = Orientation::from_rotation(&[0.0, sin, 0.0, cos])
+            else {
+                panic!("quaternion rejected");
+            };
+            assert!((converted - yaw).abs() < 1e-5, "{converted} != {yaw}");
+        }
+    }
+
+    #[test]
+    fn orientation_rejects_other_lengths() {
+        assert_eq!(Orientation::from_rotation(&[]), None);
+        assert_eq!(Orientation::from_rotation(&[0.0, 1.0]), None);
+        assert_eq!(Orientation::from_rotation(&[0.0, 0.0, 1.0]), None);
+    }
+
+    #[test]
+    fn eating_gives_the_feeding_slot_back_when_removed_or_despawned() {
+        let mut world = World::new();
+        let corpse = world
+            .spawn(Carcass {
+                food: 50,
+                feeders: 2,
+            })
+            .id();
+        let first = world.spawn(Eating { time: 0, corpse }).id();
+        let second = world.spawn(Eating { time: 0, corpse }).id();
+        world.entity_mut(first).remove::<Eating>();
+        assert_eq!(world.get::<Carcass>(corpse).unwrap().feeders, 1);
+        world.despawn(second);
+        assert_eq!(world.get::<Carcass>(corpse).unwrap().feeders, 0);
+    }
+}
@@ -1,28 +1,46 @@
-use std::sync::{Arc, atomic::AtomicPtr};
+use std::sync::{Arc, atomic::Ordering};
 
 use crate::{
-    components::DespawnCooldown,
+    behavior_tree::Node,
+    components::{
+        AiState, Archetype, BehaviorTreeState, Damaged, DespawnCooldown, Eating, Hungry,
+        IsAttacking, Orientation, PackMember, PollAliveness, Roaming, ScreamCooldown, Territory,
+        ThreatTable,
+    },
     systems::{
-        attack_hit_sys, carnivore_eating_sys, coward_sys, despawn_inactive, finish_eating_sys,
-        hostile_to_player_sys, hunger_sys, hungry_sys, remove_hungry_sys, trap_sys,
+        HERD_JOIN_RADIUS, PACK_JOIN_RADIUS, SCREAM_COOLDOWN, TERRITORY_CHARGE_RADIUS,
+        TERRITORY_RADIUS, ai_state_sys, attack_hit_sys, bear_charge_sys, bear_territory_sys,
+        behavior_tree_sys, carcass_sys, carnivore_eating_sys, check_aliveness_sys,
+        check_player_revived_sys, compact_slots, damage_reaction_sys, despawn_inactive,
+        distance_2d, end_retreat_sys, fear_sys, finish_eating_sys, flee_sys, hearing_sys,
+        herd_flee_sys, herd_sys, horde_sys, hostile_to_player_sys, hunger_sys, hungry_sys,
+        hunt_sys, interrupt_eating_sys, investigate_sys, remove_hungry_sys, returning_sys,
+        roam_sys, screamer_sys, spatial_index_sys, trap_sys, wolf_flank_sys, wolf_pack_sys,
     },
 };
-use bevy_ecs::prelude::*;
+use bevy_ecs::{prelude::*, system::RunSystemOnce};
 use chrono::Utc;
 use components::{
-    Alive, BearEntity, Carnivore, CharacterId, Coward, Dead, DeerEntity, DefaultBundle,
-    EntityDefaultBundle, H1emuEntity, HostileToPlayer, HungerLevel, PlayerEntity, Position, Trap,
-    TrapsCooldown, WolfEntity, ZombieEntity,
+    Alive, BearEntity, Bindings, Carnivore, CharacterId, Coward, Dead, DeerEntity, DefaultBundle,
+    EntityDefaultBundle, Faction, GroupMember, H1emuEntity, HerdMember, HostObject, HungerLevel,
+    Method, MethodCache, PlayerEntity, Position, Trap, TrapsCooldown, WolfEntity, ZombieEntity,
+};
+use ressources::{
+    AiDamageCallback, AiEvents, AlivenessPolling, BehaviorTrees, CarcassConfig, CharacterIndex,
+    EntityBindings, Factions, FoodValues, Groups, Herds, HungerConfig, HungerTimer, LineOfSight,
+    LiveHandles, MethodCacheStats, Noise, Noises, Packs, SpatialIndex, StateChangeCallback,
+    StateTransitions, Vision, VisionConfig,
 };
-use ressources::HungerTimer;
 use wasm_bindgen::prelude::*;
 
+mod behavior_tree;
 mod components;
 mod macros;
 mod ressources;
 mod systems;
 
 #[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum EntityType {
     Zombie,
     Player,
@@ -32,9 +50,97 @@ pub enum EntityType {
     Screamer,
 }
 
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoiseKind {
+    Footstep,
+    Gunshot,
+    Explosion,
+}
+impl NoiseKind {
+    /// How long (ms) the AI keeps reacting to it
+    pub fn reaction_duration(&self) -> i64 {
+        match self {
+            NoiseKind::Footstep => 10_000,
+            NoiseKind::Gunshot => 30_000,
+            NoiseKind::Explosion => 45_000,
+        }
+    }
+    /// Threat added to the source of the noise
+    pub fn threat(&self) -> f32 {
+        match self {
+            NoiseKind::Footstep => 1.0,
+            NoiseKind::Gunshot => 10.0,
+            NoiseKind::Explosion => 5.0,
+        }
+    }
+}
+
+/// How a faction treats another one
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Relationship {
+    Neutral,
+    /// Attacked on sight
+    Hostile,
+    /// Fled from on sight
+    Afraid,
+    /// Hunted while hungry
+    Prey,
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AiEventKind {
+    Aggro,
+    Deaggro,
+    AttackStarted,
+    AttackHit,
+    AttackMissed,
+    EatingStarted,
+    EatingFinished,
+    TrapTriggered,
+    Despawned,
+}
+
+/// What an AI entity is currently doing
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AiStateKind {
+    Idle,
+    Wandering,
+    Chasing,
+    Attacking,
+    Eating,
+    Fleeing,
+    Investigating,
+    Returning,
+}
+impl AiStateKind {
+    pub fn can_transition(&self, to: AiStateKind) -> bool {
+        use AiStateKind::*;
+        match (self, to) {
+            (from, to) if *from == to => false,
+            (_, Idle | Fleeing | Chasing) => true,
+            (Idle | Chasing | Wandering | Investigating | Eating, Attacking) => true,
+            (Idle | Wandering | Investigating | Returning, Eating) => true,
+            (Chasing | Attacking | Fleeing | Investigating, Returning) => true,
+            (Idle | Returning | Investigating, Wandering) => true,
+            (Idle | Wandering | Returning | Eating, Investigating) => true,
+            _ => false,
+        }
+    }
+}
+
 #[wasm_bindgen]
 pub struct Stats {
     pub entities: u32,
+    /// Host method calls served from the per-entity handle cache
+    pub method_cache_hits: u32,
+    /// Host method calls that had to look the method up on the object
+    pub method_cache_misses: u32,
+    /// Host objects still referenced, should follow the entity count
+    pub live_handles: u32,
 }
 
 #[wasm_bindgen]
@@ -46,87 +152,730 @@ pub struct AiManager {
 #[wasm_bindgen]
 impl AiManager {
     #[wasm_bindgen(constructor)]
-    pub fn initialize(allow_zombies: Option<bool>) -> AiManager {
+    /// `bindings` overrides the method and property names of the host entity class,
+    /// e.g. `{ goTo: "moveTo", position: "state.position" }`
+    pub fn initialize(
+        allow_zombies: Option<bool>,
+        bindings: Option<js_sys::Object>,
+    ) -> Result<AiManager, JsValue> {
+        let bindings = match bindings {
+            Some(bindings) => Bindings::parse(&bindings)?,
+            None => Bindings::default(),
+        };
         let mut world = World::new();
         let mut schedule = Schedule::default();
+        world.insert_resource(EntityBindings(Arc::new(bindings)));
+        world.insert_resource(MethodCacheStats::default());
+        world.insert_resource(LiveHandles::default());
         world.insert_resource(HungerTimer(Utc::now().timestamp_millis()));
+        world.insert_resource(AlivenessPolling::default());
+        world.insert_resource(FoodValues::default());
+        world.insert_resource(CarcassConfig::default());
+        world.insert_resource(HungerConfig::default());
+        world.insert_resource(Packs::default());
+        world.insert_resource(Herds::default());
+        world.insert_resource(Noises::default());
+        world.insert_resource(VisionConfig::default());
+        world.insert_resource(BehaviorTrees::default());
+        world.insert_resource(StateTransitions::default());
+        world.insert_resource(SpatialIndex::default());
+        world.insert_resource(AiEvents::default());
+        world.insert_resource(CharacterIndex::default());
+        world.insert_resource(Factions::default());
+        world.insert_non_send_resource(StateChangeCallback::default());
+        world.insert_non_send_resource(AiDamageCallback::default());
+        world.insert_non_send_resource(LineOfSight::default());
         if allow_zombies.is_some() && allow_zombies.unwrap() {
             schedule.add_systems(hungry_sys);
             schedule.add_systems(remove_hungry_sys);
             schedule.add_systems(hunger_sys);
-            schedule.add_systems(hostile_to_player_sys);
+            schedule.add_systems(horde_sys.before(hostile_to_player_sys));
+            schedule.add_systems(hostile_to_player_sys.after(spatial_index_sys));
+            schedule.add_systems(
+                hunt_sys
+                    .after(spatial_index_sys)
+                    .after(hostile_to_player_sys),
+            );
             schedule.add_systems(attack_hit_sys);
+            schedule.add_systems(carcass_sys);
             schedule.add_systems(carnivore_eating_sys);
             schedule.add_systems(finish_eating_sys);
-            schedule.add_systems(coward_sys);
+            schedule.add_systems(interrupt_eating_sys.before(finish_eating_sys));
+            schedule.add_systems(screamer_sys.after(spatial_index_sys));
+            schedule.add_systems(investigate_sys);
+            schedule.add_systems(roam_sys);
+            schedule.add_systems(wolf_pack_sys);
+            schedule.add_systems(wolf_flank_sys.after(wolf_pack_sys));
+            schedule.add_systems(end_retreat_sys);
+            schedule.add_systems(bear_territory_sys.after(spatial_index_sys));
+            schedule.add_systems(bear_charge_sys);
         }
         schedule.add_systems(trap_sys);
+        schedule.add_systems(spatial_index_sys);
+        schedule.add_systems(hearing_sys.after(spatial_index_sys));
+        schedule.add_systems(behavior_tree_sys);
+        schedule.add_systems(ai_state_sys);
+        schedule.add_systems(returning_sys);
+        schedule.add_systems(damage_reaction_sys.after(bear_territory_sys));
+        schedule.add_systems(fear_sys.after(spatial_index_sys).before(flee_sys));
+        schedule.add_systems(herd_flee_sys.after(fear_sys).before(flee_sys));
+        schedule.add_systems(flee_sys);
+        schedule.add_systems(herd_sys.after(herd_flee_sys));
         schedule.add_systems(despawn_inactive);
+        schedule.add_systems(check_aliveness_sys);
+        schedule.add_systems(check_player_revived_sys);
 
         log!("h1emu-ai in debug mode");
-        AiManager { world, schedule }
+        Ok(AiManager { world, schedule })
     }
 
     pub fn get_stats(&mut self) -> Stats {
+        let cache_stats = &self.world.resource::<MethodCacheStats>().0;
         Stats {
             entities: self.world.entities().len(),
+            method_cache_hits: cache_stats.hits.load(Ordering::Relaxed),
+            method_cache_misses: cache_stats.misses.load(Ordering::Relaxed),
+            live_handles: self
+                .world
+                .resource::<LiveHandles>()
+                .0
+                .load(Ordering::Relaxed),
         }
     }
 
     pub fn run(&mut self) {
         self.schedule.run(&mut self.world);
+        self.dispatch_state_transitions();
     }
-    pub fn update_pos(&mut self, entity_id: u64, position: Vec<f32>) {
-        let e = Entity::from_bits(entity_id);
-
-        let mut position_component = self.world.get_mut::<Position>(e).unwrap();
+    /// `callback(entityId, characterId, from, to, timeInPreviousState)` on every AI state change
+    pub fn on_state_change(&mut self, callback: Option<js_sys::Function>) {
+        self.world
+            .insert_non_send_resource(StateChangeCallback(callback));
+    }
+    /// `callback(attackerCharacterId, targetCharacterId)` when an AI attack lands on another AI
+    /// entity, e.g. a wolf biting a deer, players keep going through the entity applyDamage
+    pub fn on_ai_damage(&mut self, callback: Option<js_sys::Function>) {
+        self.world
+            .insert_non_send_resource(AiDamageCallback(callback));
+    }
+    pub fn update_pos(
+        &mut self,
+        entity_id: u64,
+        position: Vec<f32>,
+        rotation: Option<Vec<f32>>,
+    ) -> Result<(), JsValue> {
+        let orientation = match rotation {
+            Some(rotation) => Some(Orientation::from_rotation(&rotation).ok_or_else(|| {
+                JsValue::from_str(&format!(
+                    "rotation must be [yaw] or [x, y, z, w], got {} values",
+                    rotation.len()
+                ))
+            })?),
+            None => None,
+        };
+        let mut entity = self.get_entity_mut(entity_id)?;
+        let Some(mut position_component) = entity.get_mut::<Position>() else {
+            return Err(JsValue::from_str(&format!(
+                "entity {entity_id} has no position"
+            )));
+        };
         position_component.x = position[0];
         position_component.y = position[1];
         position_component.z = position[2];
+        if let Some(orientation) = orientation {
+            entity.insert(orientation);
+        }
+        self.world.resource_mut::<SpatialIndex>().dirty = true;
+        Ok(())
     }
-    pub fn add_entity(&mut self, e: js_sys::Object, entity_type: EntityType) -> u64 {
-        let h1emu_entity = Box::into_raw(Box::new(e));
-        let h1emu_entity_ptr = Arc::new(AtomicPtr::new(h1emu_entity));
-        let h1emu_entity_component = H1emuEntity(h1emu_entity_ptr);
+    /// Drops the method handles cached for this entity, for hosts reassigning its methods
+    pub fn invalidate_methods(&mut self, entity_id: u64) -> Result<(), JsValue> {
+        let cache = self.new_method_cache();
+        let mut entity = self.get_entity_mut(entity_id)?;
+        let Some(mut h1emu_entity) = entity.get_mut::<H1emuEntity>() else {
+            return Err(JsValue::from_str(&format!(
+                "entity {entity_id} has no host object"
+            )));
+        };
+        h1emu_entity.2 = cache;
+        Ok(())
+    }
+    /// Times `iterations` lookups of the move method (detonate for traps) by name and through
+    /// the method cache as `{ uncached, cached }` in ms
+    pub fn time_method_lookup(
+        &mut self,
+        entity_id: u64,
+        iterations: u32,
+    ) -> Result<js_sys::Object, JsValue> {
+        let entity = self.get_entity_mut(entity_id)?;
+        let method = if entity.contains::<Trap>() {
+            Method::Detonate
+        } else {
+            Method::GoTo
+        };
+        let Some(h1emu_entity) = entity.get::<H1emuEntity>() else {
+            return Err(JsValue::from_str(&format!(
+                "entity {entity_id} has no host object"
+            )));
+        };
+        let (uncached, cached) = h1emu_entity
+            .time_method_lookup(method, iterations)
+            .map_err(|err| JsValue::from_str(&err))?;
+        let result = js_sys::Object::new();
+        js_set(&result, "uncached", uncached);
+        js_set(&result, "cached", cached);
+        Ok(result)
+    }
+    /// Overrides the method and property names of the host entity class for the entities
+    /// added from now on, e.g. `{ goTo: "moveTo", position: "state.position" }`
+    pub fn set_bindings(&mut self, bindings: js_sys::Object) -> Result<(), JsValue> {
+        let bindings = Bindings::parse(&bindings)?;
+        self.world
+            .insert_resource(EntityBindings(Arc::new(bindings)));
+        Ok(())
+    }
+    pub fn add_entity(
+        &mut self,
+        e: js_sys::Object,
+        entity_type: EntityType,
+    ) -> Result<u64, JsValue> {
+        let bindings = self.world.resource::<EntityBindings>().0.clone();
+        let host_object = self.new_host_object(e);
+        let h1emu_entity_component =
+            H1emuEntity(host_object, bindings.clone(), self.new_method_cache());
+        let polling_interval = self
+            .world
+            .resource::<AlivenessPolling>()
+            .0
+            .get(&entity_type)
+            .copied();
+        let mut properties: Vec<&[String]> = vec![&bindings.position, &bindings.character_id];
+        if polling_interval.is_some() {
+            properties.push(&bindings.is_alive);
+        }
+        let despawn_consumed = self.world.resource::<CarcassConfig>().despawn_consumed;
+        if let Err(err) = h1emu_entity_component.validate(
+            &properties,
+            &bindings.required_methods(entity_type, despawn_consumed),
+        ) {
+            return Err(JsValue::from_str(&format!("{entity_type:?} {err}")));
+        }
         let position = h1emu_entity_component.get_position();
+        let orientation = h1emu_entity_component.get_orientation();
         let charid = h1emu_entity_component
             .get_characterId()
             .as_string()
             .unwrap();
+        if self.find_by_character_id(&charid).is_some() {
+            return Err(JsValue::from_str(&format!(
+                "characterId {charid} is already registered"
+            )));
+        }
+        let faction = self.world.resource::<Factions>().of(entity_type);
+        let has_tree = self
+            .world
+            .resource::<BehaviorTrees>()
+            .0
+            .contains_key(&entity_type);
         let mut entity = self.world.spawn(EntityDefaultBundle {
             h1emu_entity: h1emu_entity_component,
             position,
             character_id: CharacterId(charid),
             alive: Alive(),
         });
+        entity.insert((Archetype(entity_type), Faction(faction)));
+        if entity_type != EntityType::Player {
+            entity.insert(AiState::new(AiStateKind::Idle));
+        }
+        if let Some(orientation) = orientation {
+            entity.insert(orientation);
+        }
         match entity_type {
             EntityType::Player => entity.insert(PlayerEntity {}),
             EntityType::Zombie => entity.insert((
                 ZombieEntity {},
-                HostileToPlayer {},
+                ThreatTable::default(),
                 Carnivore {},
                 HungerLevel(0),
+                Roaming::default(),
+            )),
+            EntityType::Screamer => entity.insert((
+                ZombieEntity {},
+                ThreatTable::default(),
+                ScreamCooldown::new(SCREAM_COOLDOWN),
             )),
-            EntityType::Screamer => entity.insert((ZombieEntity {}, HostileToPlayer {})),
-            EntityType::Wolf => entity.insert((WolfEntity {}, HostileToPlayer {})),
-            EntityType::Bear => entity.insert((BearEntity {}, HostileToPlayer {})),
-            EntityType::Deer => entity.insert((DeerEntity {}, Coward {})),
+            EntityType::Wolf => entity.insert((
+                WolfEntity {},
+                ThreatTable::default(),
+                Carnivore {},
+                HungerLevel(0),
+                Roaming::default(),
+            )),
+            EntityType::Bear => entity.insert((
+                BearEntity {},
+                Territory {
+                    home: position,
+                    radius: TERRITORY_RADIUS,
+                    charge_radius: TERRITORY_CHARGE_RADIUS,
+                },
+                ThreatTable::default(),
+                Carnivore {},
+                HungerLevel(0),
+                Roaming::default(),
+            )),
+            EntityType::Deer => entity.insert((DeerEntity {}, Coward {}, ThreatTable::default())),
         };
-        entity.id().to_bits()
+        if let Some(interval) = polling_interval {
+            entity.insert(PollAliveness::new(interval));
+        }
+        if has_tree {
+            entity.insert(BehaviorTreeState::default());
+        }
+        let entity_id = entity.id();
+        if entity_type == EntityType::Wolf {
+            self.join_nearby_group::<PackMember>(entity_id, &position, PACK_JOIN_RADIUS)?;
+        }
+        if entity_type == EntityType::Deer {
+            self.join_nearby_group::<HerdMember>(entity_id, &position, HERD_JOIN_RADIUS)?;
+        }
+        self.world.resource_mut::<SpatialIndex>().dirty = true;
+        Ok(entity_id.to_bits())
     }
-    pub fn entity_dead(&mut self, entity_id: u64) {
-        let e = Entity::from_bits(entity_id);
-        self.world.entity_mut(e).remove::<Alive>();
-        self.world.entity_mut(e).insert(Dead());
+    /// Territory a bear defends, it warns players entering `radius` around `home` and charges
+    /// inside `charge_radius`
+    pub fn set_territory(
+        &mut self,
+        entity_id: u64,
+        home: Vec<f32>,
+        radius: f32,
+        charge_radius: f32,
+    ) -> Result<(), JsValue> {
+        let mut entity = self.get_entity_mut(entity_id)?;
+        if !entity.contains::<BearEntity>() {
+            return Err(JsValue::from_str(&format!(
+                "entity {entity_id} is not a bear"
+            )));
+        }
+        entity.insert(Territory {
+            home: Position {
+                x: home[0],
+                y: home[1],
+                z: home[2],
+            },
+            radius,
+            charge_radius,
+        });
+        Ok(())
+    }
+    /// Put a single entity, e.g. a player or a group of them, in another faction
+    pub fn set_faction(&mut self, entity_id: u64, faction: u32) -> Result<(), JsValue> {
+        self.get_entity_mut(entity_id)?.insert(Faction(faction));
+        Ok(())
+    }
+    /// Faction given to entities of that archetype added afterwards, defaults to the
+    /// EntityType value
+    pub fn set_archetype_faction(&mut self, entity_type: EntityType, faction: u32) {
+        self.world
+            .resource_mut::<Factions>()
+            .archetypes
+            .insert(entity_type, faction);
+    }
+    /// How members of `from` treat members of `to`, unset pairs are neutral. Hostile pairs
+    /// are chased and attacked, bears only do so after a warning inside their territory, and
+    /// a deer host class needs `applyDamage` to hit anyone
+    pub fn set_relationship(&mut self, from: u32, to: u32, relationship: Relationship) {
+        self.world
+            .resource_mut::<Factions>()
+            .relationships
+            .insert((from, to), relationship);
+    }
+    /// Move a deer into an explicit herd instead of the one picked by proximity at spawn
+    pub fn set_herd(&mut self, entity_id: u64, herd_id: u32) -> Result<(), JsValue> {
+        if !self.get_entity_mut(entity_id)?.contains::<DeerEntity>() {
+            return Err(JsValue::from_str(&format!(
+                "entity {entity_id} is not a deer"
+            )));
+        }
+        self.set_group::<HerdMember>(Entity::from_bits(entity_id), herd_id)
+    }
+    /// Move a wolf into an explicit pack instead of the one picked by proximity at spawn
+    pub fn set_pack(&mut self, entity_id: u64, pack_id: u32) -> Result<(), JsValue> {
+        if !self.get_entity_mut(entity_id)?.contains::<WolfEntity>() {
+            return Err(JsValue::from_str(&format!(
+                "entity {entity_id} is not a wolf"
+            )));
+        }
+        self.set_group::<PackMember>(Entity::from_bits(entity_id), pack_id)
+    }
+    /// Poll `isAlive` every `interval` ms for this archetype instead of relying only on
+    /// entity_dead / entity_alive, a negative interval disables it.
+    /// Fails if an entity of that archetype has no `isAlive` property
+    pub fn poll_aliveness(
+        &mut self,
+        entity_type: EntityType,
+        interval: i64,
+    ) -> Result<(), JsValue> {
+        let entities: Vec<Entity> = self
+            .world
+            .query::<(Entity, &Archetype)>()
+            .iter(&self.world)
+            .filter(|(_, archetype)| archetype.0 == entity_type)
+            .map(|(e, _)| e)
+            .collect();
+        if interval >= 0 {
+            for &e in &entities {
+                let Some(h1emu_entity) = self.world.get::<H1emuEntity>(e) else {
+                    continue;
+                };
+                if let Err(err) = h1emu_entity.validate(&[&h1emu_entity.1.is_alive], &[]) {
+                    return Err(JsValue::from_str(&format!(
+                        "{entity_type:?} {} {err}",
+                        e.to_bits()
+                    )));
+                }
+            }
+        }
+        let mut polling = self.world.resource_mut::<AlivenessPolling>();
+        if interval < 0 {
+            polling.0.remove(&entity_type);
+        } else {
+            polling.0.insert(entity_type, interval);
+        }
+        for e in entities {
+            if interval < 0 {
+                self.world.entity_mut(e).remove::<PollAliveness>();
+            } else {
+                self.world
+                    .entity_mut(e)
+                    .insert(PollAliveness::new(interval));
+            }
+        }
+        Ok(())
+    }
+    /// Hunger restored by eating a carcass of this archetype, 0 makes it inedible
+    pub fn set_food_value(&mut self, entity_type: EntityType, value: u8) {
+        self.world
+            .resource_mut::<FoodValues>()
+            .0
+            .insert(entity_type, value);
+    }
+    /// How many carnivores can feed on one body at once and whether consumed bodies
+    /// (other than players) get destroyed through the host
+    pub fn set_carcass_feeding(&mut self, feeding_slots: u8, despawn_consumed: bool) {
+        let mut carcass_config = self.world.resource_mut::<CarcassConfig>();
+        carcass_config.feeding_slots = feeding_slots;
+        carcass_config.despawn_consumed = despawn_consumed;
+    }
+    pub fn set_hunger_config(&mut self, hunger_config: HungerConfig) {
+        self.world.insert_resource(hunger_config);
+    }
+    pub fn entity_dead(&mut self, entity_id: u64) -> Result<(), JsValue> {
+        let mut entity = self.get_entity_mut(entity_id)?;
+        // the host may push the death before its isAlive flips, give the poll a full interval
+        if let Some(mut poll) = entity.get_mut::<PollAliveness>() {
+            poll.delay(Utc::now().timestamp_millis());
+        }
+        if entity.contains::<Alive>() {
+            entity.remove::<Alive>();
+            entity.insert(Dead());
+        }
+        Ok(())
+    }
+    pub fn entity_alive(&mut self, entity_id: u64) -> Result<(), JsValue> {
+        let mut entity = self.get_entity_mut(entity_id)?;
+        if let Some(mut poll) = entity.get_mut::<PollAliveness>() {
+            poll.delay(Utc::now().timestamp_millis());
+        }
+        if entity.contains::<Dead>() {
+            entity.remove::<Dead>();
+            entity.insert(Alive());
+        }
+        Ok(())
+    }
+    /// View distance and field of view (degrees) used before acquiring a target, a negative
+    /// distance goes back to the plain aggro radius
+    pub fn set_vision(&mut self, entity_type: EntityType, distance: f32, fov: f32) {
+        let mut vision_config = self.world.resource_mut::<VisionConfig>();
+        if distance < 0.0 {
+            vision_config.0.remove(&entity_type);
+        } else {
+            vision_config.0.insert(
+                entity_type,
+                Vision {
+                    distance,
+                    fov: fov.to_radians(),
+                },
+            );
+        }
+    }
+    /// `callback(from, to)` returns true when nothing blocks the view between both positions
+    pub fn set_line_of_sight(&mut self, callback: Option<js_sys::Function>) {
+        self.world.insert_non_send_resource(LineOfSight(callback));
+    }
+    /// Attach a behavior tree definition to an archetype, null detaches it
+    pub fn set_behavior_tree(
+        &mut self,
+        entity_type: EntityType,
+        tree: JsValue,
+    ) -> Result<(), JsValue> {
+        let tree = if tree.is_null() || tree.is_undefined() {
+            None
+        } else {
+            Some(Arc::new(Node::parse(&tree)?))
+        };
+        let mut trees = self.world.resource_mut::<BehaviorTrees>();
+        match &tree {
+            Some(tree) => trees.0.insert(entity_type, tree.clone()),
+            None => trees.0.remove(&entity_type),
+        };
+        let entities: Vec<Entity> = self
+            .world
+            .query::<(Entity, &Archetype)>()
+            .iter(&self.world)
+            .filter(|(_, archetype)| archetype.0 == entity_type)
+            .map(|(e, _)| e)
+            .collect();
+        // a fresh state per tree, node ids of the previous one mean nothing to it
+        for e in entities {
+            if tree.is_some() {
+                self.world
+                    .entity_mut(e)
+                    .insert(BehaviorTreeState::default());
+            } else {
+                self.world.entity_mut(e).remove::<BehaviorTreeState>();
+            }
+        }
+        Ok(())
+    }
+    /// Something loud happened, zombies investigate it, deer flee and bears get alert
+    pub fn emit_noise(
+        &mut self,
+        position: Vec<f32>,
+        radius: f32,
+        kind: NoiseKind,
+        source_character_id: Option<String>,
+    ) {
+        let source =
+            source_character_id.and_then(|character_id| self.find_by_character_id(&character_id));
+        self.world.resource_mut::<Noises>().0.push(Noise {
+            position: Position {
+                x: position[0],
+                y: position[1],
+                z: position[2],
+            },
+            radius,
+            kind,
+            source,
+        });
+    }
+    /// AI entities within `radius` of `position` as `{ entityId, characterId, archetype, distance }`,
+    /// optionally filtered by archetype and by alive (true) / dead (false).
+    /// Players are left out unless `include_players` is set or the Player archetype is asked for
+    pub fn entities_near(
+        &mut self,
+        position: Vec<f32>,
+        radius: f32,
+        entity_type: Option<EntityType>,
+        alive: Option<bool>,
+        include_players: Option<bool>,
+    ) -> js_sys::Array {
+        let include_players =
+            include_players.unwrap_or(false) || entity_type == Some(EntityType::Player);
+        if self.world.resource::<SpatialIndex>().dirty {
+            // positions or entities changed since the last run
+            if let Err(err) = self.world.run_system_once(spatial_index_sys) {
+                error!(format!("{:?}", err));
+            }
+        }
+        let center = Position {
+            x: position[0],
+            y: position[1],
+            z: position[2],
+        };
+        let result = js_sys::Array::new();
+        let index = self.world.resource::<SpatialIndex>();
+        for e in index.near(&center, radius) {
+            let Ok(entity) = self.world.get_entity(e) else {
+                continue;
+            };
+            let (Some(pos), Some(archetype), Some(character_id)) = (
+                entity.get::<Position>(),
+                entity.get::<Archetype>(),
+                entity.get::<CharacterId>(),
+            ) else {
+                continue;
+            };
+            let distance = distance_2d(&center, pos);
+            if distance > radius
+                || entity_type.is_some_and(|entity_type| entity_type != archetype.0)
+                || alive.is_some_and(|alive| alive != entity.contains::<Alive>())
+                || (!include_players && archetype.0 == EntityType::Player)
+            {
+                continue;
+            }
+            let item = js_sys::Object::new();
+            js_set(&item, "entityId", e.to_bits());
+            js_set(&item, "characterId", character_id.0.as_str());
+            js_set(&item, "archetype", archetype.0);
+            js_set(&item, "distance", distance);
+            result.push(&item);
+        }
+        result
+    }
+    /// AI decisions since the last call as `{ kind, entityId, characterId, target,
+    /// targetCharacterId, time }`.
+    /// Only the latest 1024 are kept between two calls
+    pub fn drain_events(&mut self) -> js_sys::Array {
+        let events = std::mem::take(&mut self.world.resource_mut::<AiEvents>().0);
+        let result = js_sys::Array::new();
+        for event in events {
+            let obj = js_sys::Object::new();
+            js_set(&obj, "kind", event.kind);
+            js_set(&obj, "entityId", event.entity.to_bits());
+            if let Some(character_id) = event.character_id {
+                js_set(&obj, "characterId", character_id);
+            }
+            if let Some(target) = event.target {
+                js_set(&obj, "target", target.to_bits());
+                if let Some(character_id) = event.target_character_id {
+                    js_set(&obj, "targetCharacterId", character_id);
+                }
+            }
+            js_set(&obj, "time", event.time as f64);
+            result.push(&obj);
+        }
+        result
     }
-    pub fn entity_alive(&mut self, entity_id: u64) {
+    /// Every AI component of an entity as a plain object, undefined if the entity doesn't exist
+    pub fn inspect(&self, entity_id: u64) -> JsValue {
         let e = Entity::from_bits(entity_id);
-        self.world.entity_mut(e).remove::<Dead>();
-        self.world.entity_mut(e).insert(Alive());
+        let Ok(entity) = self.world.get_entity(e) else {
+            return JsValue::UNDEFINED;
+        };
+        let current_time = Utc::now().timestamp_millis();
+        let obj = js_sys::Object::new();
+        js_set(&obj, "entityId", entity_id);
+        if let Some(character_id) = entity.get::<CharacterId>() {
+            js_set(&obj, "characterId", character_id.0.as_str());
+        }
+        if let Some(archetype) = entity.get::<Archetype>() {
+            js_set(&obj, "archetype", archetype.0);
+        }
+        if let Some(faction) = entity.get::<Faction>() {
+            js_set(&obj, "faction", faction.0);
+        }
+        if let Some(pos) = entity.get::<Position>() {
+            js_set(
+                &obj,
+                "position",
+                js_sys::Float32Array::from(&[pos.x, pos.y, pos.z][..]),
+            );
+        }
+        js_set(&obj, "alive", entity.contains::<Alive>());
+        js_set(&obj, "dead", entity.contains::<Dead>());
+        if let Some(state) = entity.get::<AiState>() {
+            js_set(&obj, "state", state.kind);
+            js_set(
+                &obj,
+                "timeInState",
+                state.time_in_state(current_time) as f64,
+            );
+        }
+        if let Some(hunger_level) = entity.get::<HungerLevel>() {
+            js_set(&obj, "hungerLevel", hunger_level.0);
+            js_set(&obj, "hungry", entity.contains::<Hungry>());
+        }
+        if let Some(attack) = entity.get::<IsAttacking>() {
+            let attack_obj = js_sys::Object::new();
+            js_set(&attack_obj, "target", attack.target.to_bits());
+            if let Some(character_id) = self.world.get::<CharacterId>(attack.target) {
+                js_set(&attack_obj, "targetCharacterId", character_id.0.as_str());
+            }
+            js_set(&attack_obj, "timeToHit", attack.time_to_hit as f64);
+            js_set(&obj, "attack", attack_obj);
+        }
+        if let Some(eating) = entity.get::<Eating>() {
+            js_set(&obj, "eatingStart", eating.time as f64);
+            js_set(&obj, "eatingCorpse", eating.corpse.to_bits());
+        }
+        if let Some(trap_cooldown) = entity.get::<TrapsCooldown>() {
+            let trap_obj = js_sys::Object::new();
+            js_set(&trap_obj, "lastTrigger", trap_cooldown.last_trigger as f64);
+            js_set(&trap_obj, "cooldown", trap_cooldown.cooldown as f64);
+            js_set(&trap_obj, "inCooldown", trap_cooldown.is_in_cooldown());
+            js_set(&obj, "trap", trap_obj);
+        }
+        if let Some(despawn_cooldown) = entity.get::<DespawnCooldown>() {
+            js_set(
+                &obj,
+                "despawnCooldownRemaining",
+                despawn_cooldown.remaining() as f64,
+            );
+        }
+        obj.into()
+    }
+    /// Report that an entity got hit, attacker_character_id is left out for environmental
+    /// damage
+    pub fn on_damaged(
+        &mut self,
+        entity_id: u64,
+        amount: f32,
+        attacker_character_id: Option<String>,
+    ) -> Result<(), JsValue> {
+        let current_time = Utc::now().timestamp_millis();
+        let attacker =
+            attacker_character_id.and_then(|character_id| self.find_by_character_id(&character_id));
+        let mut entity = self.get_entity_mut(entity_id)?;
+        if let (Some(attacker), Some(mut threat_table)) =
+            (attacker, entity.get_mut::<ThreatTable>())
+        {
+            threat_table.add_damage(attacker, amount, current_time);
+        }
+        entity.insert(Damaged {
+            attacker,
+            amount,
+            time: current_time,
+        });
+        Ok(())
     }
     pub fn remove_entity(&mut self, entity_id_bits: u64) {
         let e = Entity::from_bits(entity_id_bits);
+        let pack = self.world.get::<PackMember>(e).map(PackMember::group);
+        let herd = self.world.get::<HerdMember>(e).map(HerdMember::group);
         self.world.despawn(e);
+        if let Some(pack) = pack {
+            self.leave_group::<PackMember>(pack);
+        }
+        if let Some(herd) = herd {
+            self.leave_group::<HerdMember>(herd);
+        }
+        self.world.resource_mut::<SpatialIndex>().dirty = true;
+    }
+    pub fn update_pos_by_character_id(
+        &mut self,
+        character_id: &str,
+        position: Vec<f32>,
+        rotation: Option<Vec<f32>>,
+    ) -> Result<(), JsValue> {
+        let entity_id = self.entity_id_of(character_id)?;
+        self.update_pos(entity_id, position, rotation)
+    }
+    pub fn entity_dead_by_character_id(&mut self, character_id: &str) -> Result<(), JsValue> {
+        let entity_id = self.entity_id_of(character_id)?;
+        self.entity_dead(entity_id)
+    }
+    pub fn entity_alive_by_character_id(&mut self, character_id: &str) -> Result<(), JsValue> {
+        let entity_id = self.entity_id_of(character_id)?;
+        self.entity_alive(entity_id)
+    }
+    pub fn remove_entity_by_character_id(&mut self, character_id: &str) -> Result<(), JsValue> {
+        let entity_id = self.entity_id_of(character_id)?;
+        self.remove_entity(entity_id);
+        Ok(())
+    }
+    pub fn inspect_by_character_id(&self, character_id: &str) -> JsValue {
+        self.find_by_character_id(character_id)
+            .map_or(JsValue::UNDEFINED, |e| self.inspect(e.to_bits()))
     }
     pub fn add_trap(
         &mut self,
@@ -134,10 +883,18 @@ impl AiManager {
         radius: f32,
         trigger_cooldown: i64,
         despawn_cooldown: Option<i64>,
-    ) -> u64 {
-        let h1emu_entity = Box::into_raw(Box::new(e));
-        let h1emu_entity_ptr = Arc::new(AtomicPtr::new(h1emu_entity));
-        let h1emu_entity_component = H1emuEntity(h1emu_entity_ptr);
+    ) -> Result<u64, JsValue> {
+        let bindings = self.world.resource::<EntityBindings>().0.clone();
+        let host_object = self.new_host_object(e);
+        let h1emu_entity_component =
+            H1emuEntity(host_object, bindings.clone(), self.new_method_cache());
+        let mut methods = vec![bindings.detonate.as_str()];
+        if despawn_cooldown.is_some() {
+            methods.push(&bindings.destroy);
+        }
+        if let Err(err) = h1emu_entity_component.validate(&[&bindings.position], &methods) {
+            return Err(JsValue::from_str(&format!("trap {err}")));
+        }
         let position = h1emu_entity_component.get_position();
         let mut entity = self.world.spawn(DefaultBundle {
             h1emu_entity: h1emu_entity_component,
@@ -152,6 +909,178 @@ impl AiManager {
             log!("spawned with cooldown");
             entity.insert(DespawnCooldown::new(despawn_cooldown));
         }
-        entity.id().to_bits()
+        let entity_id = entity.id();
+        self.world.resource_mut::<SpatialIndex>().dirty = true;
+        Ok(entity_id.to_bits())
+    }
+}
+impl AiManager {
+    fn dispatch_state_transitions(&mut self) {
+        let transitions = std::mem::take(&mut self.world.resource_mut::<StateTransitions>().0);
+        let Some(callback) = &self.world.non_send_resource::<StateChangeCallback>().0 else {
+            return;
+        };
+        for transition in transitions {
+            let character_id = self
+                .world
+                .get::<CharacterId>(transition.entity)
+                .map_or(JsValue::UNDEFINED, |character_id| {
+                    JsValue::from_str(&character_id.0)
+                });
+            let args = js_sys::Array::new();
+            args.push(&JsValue::from(transition.entity.to_bits()));
+            args.push(&character_id);
+            args.push(&JsValue::from(transition.from));
+            args.push(&JsValue::from(transition.to));
+            args.push(&JsValue::from(transition.time_in_state as f64));
+            if let Err(err) = callback.apply(&JsValue::NULL, &args) {
+                error!(format!("{:?}", err));
+            }
+        }
+    }
+    /// Joins the group of a live member within `radius` or starts a new one
+    fn join_nearby_group<T: GroupMember>(
+        &mut self,
+        e: Entity,
+        position: &Position,
+        radius: f32,
+    ) -> Result<(), JsValue> {
+        let nearby = self
+            .world
+            .query_filtered::<(&Position, &T), With<Alive>>()
+            .iter(&self.world)
+            .find(|(member_pos, _)| distance_2d(position, member_pos) <= radius)
+            .map(|(_, member)| member.group());
+        let group =
+            nearby.unwrap_or_else(|| self.world.resource_mut::<Groups<T::State>>().create());
+        self.set_group::<T>(e, group)
+    }
+    /// Moves `e` to `group`, leaving the previous one, and keeps both slot ranges compact
+    fn set_group<T: GroupMember>(&mut self, e: Entity, group: u32) -> Result<(), JsValue> {
+        let previous = self.get_entity_mut(e.to_bits())?.get::<T>().map(T::group);
+        if previous == Some(group) {
+            return Ok(());
+        }
+        let slot = self.world.resource_mut::<Groups<T::State>>().join(group);
+        self.get_entity_mut(e.to_bits())?
+            .insert(T::new(group, slot));
+        if let Some(previous) = previous {
+            self.leave_group::<T>(previous);
+        }
+        self.compact_group::<T>(group);
+        Ok(())
+    }
+    fn leave_group<T: GroupMember>(&mut self, group: u32) {
+        self.world.resource_mut::<Groups<T::State>>().leave(group);
+        self.compact_group::<T>(group);
+    }
+    fn compact_group<T: GroupMember>(&mut self, group: u32) {
+        let mut query = self.world.query_filtered::<&mut T, With<Alive>>();
+        compact_slots(
+            query
+                .iter_mut(&mut self.world)
+                .filter(|member| member.group() == group),
+        );
+    }
+    fn new_host_object(&self, obj: js_sys::Object) -> Arc<HostObject> {
+        let live_handles = self.world.resource::<LiveHandles>().0.clone();
+        Arc::new(HostObject::new(obj, live_handles))
+    }
+    fn new_method_cache(&self) -> Arc<MethodCache> {
+        let counters = self.world.resource::<MethodCacheStats>().0.clone();
+        Arc::new(MethodCache::new(counters))
+    }
+    fn get_entity_mut(&mut self, entity_id: u64) -> Result<EntityWorldMut<'_>, JsValue> {
+        self.world
+            .get_entity_mut(Entity::from_bits(entity_id))
+            .map_err(|_| JsValue::from_str(&format!("unknown entity {entity_id}")))
+    }
+    fn find_by_character_id(&self, character_id: &str) -> Option<Entity> {
+        self.world
+            .resource::<CharacterIndex>()
+            .0
+            .get(character_id)
+            .copied()
+    }
+    fn entity_id_of(&self, character_id: &str) -> Result<u64, JsValue> {
+        self.find_by_character_id(character_id)
+            .map(Entity::to_bits)
+            .ok_or_else(|| JsValue::from_str(&format!("unknown characterId {character_id}")))
+    }
+}
+
+fn js_set(obj: &js_sys::Object, key: &str, value: impl Into<JsValue>) {
+    if let Err(err) = js_sys::Reflect::set(obj, &JsValue::from_str(key), &value.into()) {
+        error!(format!("{:?}", err));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen::JsCast;
+
+    use super::*;
+
+    fn manager() -> AiManager {
+        let mut world = World::new();
+        world.insert_resource(EntityBindings::default());
+        world.insert_resource(MethodCacheStats::default());
+        world.insert_resource(LiveHandles::default());
+        world.insert_resource(Packs::default());
+        world.insert_resource(SpatialIndex::default());
+        AiManager {
+            world,
+            schedule: Schedule::default(),
+        }
+    }
+
+    /// Wraps `undefined` as host object, it is released without calling into JS
+    fn spawn(manager: &mut AiManager) -> Entity {
+        let h1emu_entity = H1emuEntity(
+            manager.new_host_object(JsValue::UNDEFINED.unchecked_into()),
+            manager.world.resource::<EntityBindings>().0.clone(),
+            manager.new_method_cache(),
+        );
+        manager
+            .world
+            .spawn(DefaultBundle {
+                h1emu_entity,
+                position: Position::default(),
+            })
+            .id()
+    }
+
+    #[test]
+    fn remove_entity_releases_the_host_object() {
+        let mut manager = manager();
+        let first = spawn(&mut manager);
+        let second = spawn(&mut manager);
+        assert_eq!(manager.get_stats().live_handles, 2);
+        manager.remove_entity(first.to_bits());
+        assert_eq!(manager.get_stats().live_handles, 1);
+        manager.remove_entity(second.to_bits());
+        assert_eq!(manager.get_stats().live_handles, 0);
+    }
+
+    #[test]
+    fn despawn_releases_the_host_object() {
+        let mut manager = manager();
+        let e = spawn(&mut manager);
+        assert_eq!(manager.get_stats().live_handles, 1);
+        manager.world.despawn(e);
+        assert_eq!(manager.get_stats().live_handles, 0);
+    }
+
+    #[test]
+    fn live_handles_are_counted_per_world() {
+        let mut first = manager();
+        let mut second = manager();
+        spawn(&mut first);
+        spawn(&mut first);
+        spawn(&mut second);
+        assert_eq!(first.get_stats().live_handles, 2);
+        assert_eq!(second.get_stats().live_handles, 1);
+        drop(first);
+        assert_eq!(second.get_stats().live_handles, 1);
     }
 }
@@ -1,4 +1,461 @@
-use bevy_ecs::resource::Resource;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, atomic::AtomicU32},
+};
+
+use bevy_ecs::{entity::Entity, resource::Resource};
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    AiEventKind, AiStateKind, EntityType, NoiseKind, Relationship,
+    behavior_tree::Node,
+    components::{Bindings, MethodCacheCounters, Position},
+};
 
 #[derive(Resource)]
 pub struct HungerTimer(pub i64);
+
+/// Aliveness polling interval (ms) per archetype
+#[derive(Resource, Default)]
+pub struct AlivenessPolling(pub HashMap<EntityType, i64>);
+
+/// Hunger restored when eating a carcass of each archetype, 0 means not edible
+#[derive(Resource)]
+pub struct FoodValues(pub HashMap<EntityType, u8>);
+impl Default for FoodValues {
+    fn default() -> Self {
+        FoodValues(HashMap::from([
+            (EntityType::Player, 100),
+            (EntityType::Deer, 60),
+            (EntityType::Wolf, 40),
+            (EntityType::Bear, 100),
+        ]))
+    }
+}
+impl FoodValues {
+    pub fn get(&self, entity_type: EntityType) -> u8 {
+        self.0.get(&entity_type).copied().unwrap_or(0)
+    }
+}
+
+#[derive(Resource)]
+pub struct CarcassConfig {
+    pub feeding_slots: u8,
+    pub despawn_consumed: bool,
+}
+impl Default for CarcassConfig {
+    fn default() -> Self {
+        CarcassConfig {
+            feeding_slots: 3,
+            despawn_consumed: false,
+        }
+    }
+}
+
+/// Hunger thresholds and how hunger changes carnivore behavior
+#[wasm_bindgen]
+#[derive(Resource, Clone, Copy)]
+pub struct HungerConfig {
+    pub hungry_threshold: u8,
+    pub sated_threshold: u8,
+    pub decay_amount: u8,
+    pub decay_interval: i64,
+    pub aggro_radius: f32,
+    pub hungry_aggro_radius: f32,
+    pub attack_delay: i64,
+    pub hungry_attack_delay: i64,
+    /// Roaming is opt-in, a radius of 0 leaves wandering in that hunger band to the host.
+    /// Carnivores spawn at hunger 0 so `starving_roam_radius` applies from the first tick
+    pub roam_radius: f32,
+    pub starving_roam_radius: f32,
+    pub linger_radius: f32,
+    pub roam_interval: i64,
+}
+impl Default for HungerConfig {
+    fn default() -> Self {
+        HungerConfig {
+            hungry_threshold: 10,
+            sated_threshold: 50,
+            decay_amount: 1,
+            decay_interval: 10_000,
+            aggro_radius: 1.5,
+            hungry_aggro_radius: 6.0,
+            attack_delay: 1000,
+            hungry_attack_delay: 600,
+            roam_radius: 0.0,
+            starving_roam_radius: 0.0,
+            linger_radius: 0.0,
+            roam_interval: 15_000,
+        }
+    }
+}
+#[wasm_bindgen]
+impl HungerConfig {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> HungerConfig {
+        HungerConfig::default()
+    }
+}
+impl HungerConfig {
+    pub fn aggro_radius(&self, hungry: bool) -> f32 {
+        if hungry {
+            self.hungry_aggro_radius
+        } else {
+            self.aggro_radius
+        }
+    }
+    pub fn attack_delay(&self, hungry: bool) -> i64 {
+        if hungry {
+            self.hungry_attack_delay
+        } else {
+            self.attack_delay
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct PackState {
+    pub target: Option<Entity>,
+    pub last_target_pos: Option<Position>,
+    /// Members the pack had at full strength, used for slots and to know when to retreat
+    pub size: u8,
+    pub retreat_until: i64,
+}
+
+#[derive(Default)]
+pub struct HerdState {
+    /// Wanders for the whole herd, re-elected when it dies
+    pub leader: Option<Entity>,
+    pub size: u8,
+    pub next_move: i64,
+}
+
+/// State shared by the members of a pack or a herd
+pub trait GroupState: Default + Send + Sync + 'static {
+    /// Members counted for the slots
+    fn size_mut(&mut self) -> &mut u8;
+}
+impl GroupState for PackState {
+    fn size_mut(&mut self) -> &mut u8 {
+        &mut self.size
+    }
+}
+impl GroupState for HerdState {
+    fn size_mut(&mut self) -> &mut u8 {
+        &mut self.size
+    }
+}
+/// Packs or herds by id, each member holds a slot below the size of its group
+#[derive(Resource, Default)]
+pub struct Groups<S: GroupState> {
+    pub groups: HashMap<u32, S>,
+    next_id: u32,
+}
+impl<S: GroupState> Groups<S> {
+    pub fn create(&mut self) -> u32 {
+        while self.groups.contains_key(&self.next_id) {
+            self.next_id += 1;
+        }
+        self.groups.insert(self.next_id, S::default());
+        self.next_id
+    }
+    /// Returns the slot of the new member
+    pub fn join(&mut self, group_id: u32) -> u8 {
+        let size = self.groups.entry(group_id).or_default().size_mut();
+        let slot = *size;
+        *size = size.saturating_add(1);
+        slot
+    }
+    /// A member moved to another group or got removed, it no longer counts as a loss
+    pub fn leave(&mut self, group_id: u32) {
+        if let Some(group) = self.groups.get_mut(&group_id) {
+            let size = group.size_mut();
+            *size = size.saturating_sub(1);
+        }
+    }
+}
+pub type Packs = Groups<PackState>;
+pub type Herds = Groups<HerdState>;
+
+pub struct Noise {
+    pub position: Position,
+    pub radius: f32,
+    pub kind: NoiseKind,
+    pub source: Option<Entity>,
+}
+/// Noises emitted since the last run
+#[derive(Resource, Default)]
+pub struct Noises(pub Vec<Noise>);
+
+#[derive(Clone, Copy)]
+pub struct Vision {
+    pub distance: f32,
+    /// Full view cone angle in radians
+    pub fov: f32,
+}
+/// Archetypes without vision fall back to the aggro radius
+#[derive(Resource, Default)]
+pub struct VisionConfig(pub HashMap<EntityType, Vision>);
+
+/// Optional host query telling if something blocks the view between two positions
+#[derive(Default)]
+pub struct LineOfSight(pub Option<js_sys::Function>);
+
+/// Behavior trees attached per archetype, evaluated alongside the built-in systems
+#[derive(Resource, Default)]
+pub struct BehaviorTrees(pub HashMap<EntityType, Arc<Node>>);
+
+pub struct StateTransition {
+    pub entity: Entity,
+    pub from: AiStateKind,
+    pub to: AiStateKind,
+    pub time_in_state: i64,
+}
+/// Transitions since the last dispatch to the host
+#[derive(Resource, Default)]
+pub struct StateTransitions(pub Vec<StateTransition>);
+#[derive(Default)]
+pub struct StateChangeCallback(pub Option<js_sys::Function>);
+/// Host hook applying the damage of an AI attack landing on another AI entity
+#[derive(Default)]
+pub struct AiDamageCallback(pub Option<js_sys::Function>);
+
+pub const SPATIAL_CELL_SIZE: f32 = 16.0;
+/// Uniform grid over x/z rebuilt every run, candidates still need a distance check
+#[derive(Resource, Default)]
+pub struct SpatialIndex {
+    cells: HashMap<(i32, i32), Vec<Entity>>,
+    pub dirty: bool,
+}
+impl SpatialIndex {
+    fn cell(x: f32, z: f32) -> (i32, i32) {
+        (
+            (x / SPATIAL_CELL_SIZE).floor() as i32,
+            (z / SPATIAL_CELL_SIZE).floor() as i32,
+        )
+    }
+    /// Keeps the buckets filled last time for reuse and drops the ones nobody stood in
+    pub fn clear(&mut self) {
+        self.cells.retain(|_, bucket| {
+            let used = !bucket.is_empty();
+            bucket.clear();
+            used
+        });
+        self.dirty = false;
+    }
+    pub fn insert(&mut self, entity: Entity, pos: &Position) {
+        self.cells
+            .entry(SpatialIndex::cell(pos.x, pos.z))
+            .or_default()
+            .push(entity);
+    }
+    /// Entities in the cells covering the square of `radius` around `center`
+    pub fn near(&self, center: &Position, radius: f32) -> impl Iterator<Item = Entity> + '_ {
+        let (min_x, min_z) = SpatialIndex::cell(center.x - radius, center.z - radius);
+        let (max_x, max_z) = SpatialIndex::cell(center.x + radius, center.z + radius);
+        (min_x..=max_x)
+            .flat_map(move |x| (min_z..=max_z).map(move |z| (x, z)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+    }
+}
+
+/// Past that the oldest events are dropped, a host that never drains them doesn't leak
+pub const MAX_AI_EVENTS: usize = 1024;
+
+pub struct AiEvent {
+    pub kind: AiEventKind,
+    pub entity: Entity,
+    /// Taken when the event happened, the entity may be despawned by the time it's drained
+    pub character_id: Option<String>,
+    pub target: Option<Entity>,
+    pub target_character_id: Option<String>,
+    pub time: i64,
+}
+/// Decisions waiting to be drained by the host
+#[derive(Resource, Default)]
+pub struct AiEvents(pub VecDeque<AiEvent>);
+impl AiEvents {
+    pub fn push(&mut self, event: AiEvent) {
+        if self.0.len() >= MAX_AI_EVENTS {
+            self.0.pop_front();
+        }
+        self.0.push_back(event);
+    }
+}
+
+/// Method and property names shared by every host entity of this manager
+#[derive(Resource, Default)]
+pub struct EntityBindings(pub Arc<Bindings>);
+/// Hits and misses of the host method caches of this world
+#[derive(Resource, Default)]
+pub struct MethodCacheStats(pub Arc<MethodCacheCounters>);
+/// Host objects this world still holds a reference to
+#[derive(Resource, Default)]
+pub struct LiveHandles(pub Arc<AtomicU32>);
+
+/// Host characterId of every registered entity
+#[derive(Resource, Default)]
+pub struct CharacterIndex(pub HashMap<String, Entity>);
+
+/// Faction of each archetype and how factions treat each other
+#[derive(Resource)]
+pub struct Factions {
+    pub archetypes: HashMap<EntityType, u32>,
+    pub relationships: HashMap<(u32, u32), Relationship>,
+}
+impl Default for Factions {
+    fn default() -> Self {
+        let archetypes = [
+            EntityType::Zombie,
+            EntityType::Player,
+            EntityType::Deer,
+            EntityType::Wolf,
+            EntityType::Bear,
+            EntityType::Screamer,
+        ]
+        .into_iter()
+        .map(|entity_type| (entity_type, entity_type as u32))
+        .collect();
+        let mut relationships = HashMap::new();
+        // bears warn then charge the ones they are hostile to inside their territory
+        for hostile in [
+            EntityType::Zombie,
+            EntityType::Screamer,
+            EntityType::Wolf,
+            EntityType::Bear,
+        ] {
+            relationships.insert(
+                (hostile as u32, EntityType::Player as u32),
+                Relationship::Hostile,
+            );
+        }
+        for predator in [EntityType::Wolf, EntityType::Bear] {
+            relationships.insert(
+                (predator as u32, EntityType::Deer as u32),
+                Relationship::Prey,
+            );
+        }
+        for predator in [
+            EntityType::Player,
+            EntityType::Zombie,
+            EntityType::Wolf,
+            EntityType::Bear,
+        ] {
+            relationships.insert(
+                (EntityType::Deer as u32, predator as u32),
+                Relationship::Afraid,
+            );
+        }
+        Factions {
+            archetypes,
+            relationships,
+        }
+    }
+}
+impl Factions {
+    pub fn of(&self, entity_type: EntityType) -> u32 {
+        self.archetypes
+            .get(&entity_type)
+            .copied()
+            .unwrap_or(entity_type as u32)
+    }
+    pub fn relationship(&self, from: u32, to: u32) -> Relationship {
+        self.relationships
+            .get(&(from, to))
+            .copied()
+            .unwrap_or(Relationship::Neutral)
+    }
+    pub fn is_afraid_of_any(&self, from: u32) -> bool {
+        self.relationships
+            .iter()
+            .any(|(&(other, _), &relationship)| {
+                other == from && relationship == Relationship::Afraid
+            })
+    }
+    /// Anything but its own faction and what it's afraid of
+    pub fn can_eat(&self, from: u32, to: u32) -> bool {
+        from != to && self.relationship(from, to) != Relationship::Afraid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32, z: f32) -> Position {
+        Position { x, y: 0.0, z }
+    }
+
+    fn near(index: &SpatialIndex, center: &Position, radius: f32) -> Vec<Entity> {
+        let mut found: Vec<Entity> = index.near(center, radius).collect();
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn spatial_index_splits_cells_on_the_boundary() {
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut index = SpatialIndex::default();
+        index.insert(a, &at(SPATIAL_CELL_SIZE - 0.01, 0.0));
+        index.insert(b, &at(SPATIAL_CELL_SIZE, 0.0));
+        assert_eq!(near(&index, &at(8.0, 8.0), 1.0), vec![a]);
+        assert_eq!(near(&index, &at(24.0, 8.0), 1.0), vec![b]);
+        // a square reaching over the boundary covers both cells
+        assert_eq!(near(&index, &at(SPATIAL_CELL_SIZE, 8.0), 1.0), vec![a, b]);
+    }
+
+    #[test]
+    fn spatial_index_rounds_negative_coordinates_down() {
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut index = SpatialIndex::default();
+        index.insert(a, &at(-0.01, -0.01));
+        index.insert(b, &at(0.0, 0.0));
+        assert_eq!(near(&index, &at(-8.0, -8.0), 1.0), vec![a]);
+        assert_eq!(near(&index, &at(8.0, 8.0), 1.0), vec![b]);
+        assert_eq!(
+            near(&index, &at(-SPATIAL_CELL_SIZE * 2.5, 0.0), 1.0),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn spatial_index_clear_empties_and_drops_unused_cells() {
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut index = SpatialIndex::default();
+        index.insert(a, &at(0.0, 0.0));
+        index.insert(b, &at(100.0, 100.0));
+        index.dirty = true;
+        index.clear();
+        assert!(!index.dirty);
+        assert_eq!(near(&index, &at(0.0, 0.0), 1.0), vec![]);
+        assert_eq!(index.cells.len(), 2);
+        index.insert(a, &at(0.0, 0.0));
+        index.clear();
+        assert_eq!(index.cells.len(), 1);
+        index.clear();
+        assert!(index.cells.is_empty());
+    }
+
+    #[test]
+    fn ai_events_drop_the_oldest_past_the_cap() {
+        let mut events = AiEvents::default();
+        for time in 0..MAX_AI_EVENTS as i64 + 10 {
+            events.push(AiEvent {
+                kind: AiEventKind::Aggro,
+                entity: Entity::from_raw(1),
+                character_id: None,
+                target: None,
+                target_character_id: None,
+                time,
+            });
+        }
+        assert_eq!(events.0.len(), MAX_AI_EVENTS);
+        assert_eq!(events.0.front().map(|event| event.time), Some(10));
+        assert_eq!(
+            events.0.back().map(|event| event.time),
+            Some(MAX_AI_EVENTS as i64 + 9)
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use bevy_ecs::prelude::*;
+use chrono::Utc;
+use wasm_bindgen::JsValue;
+
+use crate::{
+    Relationship,
+    components::{
+        Alive, BehaviorTreeState, Charging, Chasing, Damaged, Faction, H1emuEntity, HordeSlot,
+        IsAttacking, Position, Returning, Territory, Warning,
+    },
+    log,
+    ressources::{Factions, SpatialIndex},
+    systems::common::{distance_2d, go_to_args},
+};
+
+pub const TERRITORY_RADIUS: f32 = 25.0;
+pub const TERRITORY_CHARGE_RADIUS: f32 = 10.0;
+/// How much closer than at the warning an intruder can get before the bear charges
+const APPROACH_TOLERANCE: f32 = 3.0;
+const CHARGE_ORDER_INTERVAL: i64 = 500;
+pub const BEAR_RAGE_DURATION: i64 = 20_000;
+const RETURN_TIMEOUT: i64 = 60_000;
+
+/// Warns whoever it is hostile to entering the territory and charges the ones that keep
+/// coming or attack
+#[allow(clippy::type_complexity)]
+pub fn bear_territory_sys(
+    query: Query<
+        (
+            Entity,
+            &H1emuEntity,
+            &Position,
+            &Territory,
+            &Faction,
+            Option<&Warning>,
+            Option<Ref<Damaged>>,
+        ),
+        (With<Alive>, Without<Charging>, Without<BehaviorTreeState>),
+    >,
+    intruder_query: Query<(&Position, &Faction), With<Alive>>,
+    factions: Res<Factions>,
+    spatial_index: Res<SpatialIndex>,
+    mut commands: Commands,
+) {
+    for (ent, h1emu_ent, pos, territory, faction, warning, damaged) in &query {
+        let intruder = spatial_index
+            .near(&territory.home, territory.radius)
+            .filter(|&e| e != ent)
+            .filter_map(|e| intruder_query.get(e).ok().map(|intruder| (e, intruder)))
+            .find(|(_, (intruder_pos, intruder_faction))| {
+                factions.relationship(faction.0, intruder_faction.0) == Relationship::Hostile
+                    && distance_2d(&territory.home, intruder_pos) <= territory.radius
+            })
+            .map(|(e, (intruder_pos, _))| (e, intruder_pos));
+        let Some((intruder_ent, intruder_pos)) = intruder else {
+            if warning.is_some() {
+                commands.entity(ent).remove::<Warning>();
+            }
+            continue;
+        };
+        let distance = distance_2d(pos, intruder_pos);
+        let attacked = damaged.is_some_and(|damaged| damaged.is_changed());
+        let charge = match warning {
+            _ if attacked => true,
+            Some(warning) => {
+                warning.target == intruder_ent
+                    && (distance <= territory.charge_radius
+                        || distance < warning.distance - APPROACH_TOLERANCE)
+            }
+            None => false,
+        };
+        if charge {
+            log!("bear charging");
+            let mut e_cmds = commands.entity(ent);
+            e_cmds.remove::<Warning>();
+            e_cmds.insert(Charging {
+                target: intruder_ent,
+                next_order: 0,
+                enraged_until: 0,
+            });
+        } else if warning.is_none_or(|warning| warning.target != intruder_ent) {
+            let args = js_sys::Array::new();
+            args.push(&JsValue::from_str("Roar"));
+            h1emu_ent.play_animation(&args);
+            commands.entity(ent).insert(Warning {
+                target: intruder_ent,
+                distance,
+            });
+        }
+    }
+}
+
+/// Runs at the target until it leaves the territory (or the rage ends) then goes back home
+pub fn bear_charge_sys(
+    mut query: Query<(Entity, &H1emuEntity, &Territory, &mut Charging), With<Alive>>,
+    target_query: Query<&Position, With<Alive>>,
+    mut commands: Commands,
+) {
+    let current_time = Utc::now().timestamp_millis();
+    for (ent, h1emu_ent, territory, mut charging) in &mut query {
+        let target_pos = target_query.get(charging.target).ok().filter(|target_pos| {
+            charging.enraged_until > current_time
+                || distance_2d(&territory.home, target_pos) <= territory.radius
+        });
+        let Some(target_pos) = target_pos else {
+            log!("bear disengaging");
+            let mut e_cmds = commands.entity(ent);
+            e_cmds.remove::<(Charging, Chasing, HordeSlot, IsAttacking)>();
+            e_cmds.insert(Returning {
+                destination: territory.home,
+                until: current_time + RETURN_TIMEOUT,
+            });
+            h1emu_ent.go_to(&go_to_args(&territory.home));
+            continue;
+        };
+        if charging.next_order <= current_time {
+            h1emu_ent.go_to(&go_to_args(target_pos));
+            charging.next_order = current_time + CHARGE_ORDER_INTERVAL;
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use bevy_ecs::prelude::*;
+use chrono::Utc;
+
+use crate::{
+    behavior_tree::TickContext,
+    components::{
+        Alive, Archetype, BehaviorTreeState, CharacterId, H1emuEntity, Hungry, PlayerEntity,
+        Position,
+    },
+    ressources::BehaviorTrees,
+    systems::common::distance_2d,
+};
+
+#[allow(clippy::type_complexity)]
+pub fn behavior_tree_sys(
+    mut query: Query<
+        (
+            &Archetype,
+            &H1emuEntity,
+            &Position,
+            Has<Hungry>,
+            &mut BehaviorTreeState,
+        ),
+        With<Alive>,
+    >,
+    player_query: Query<(&Position, &CharacterId), (With<PlayerEntity>, With<Alive>)>,
+    trees: Res<BehaviorTrees>,
+) {
+    if trees.0.is_empty() {
+        return;
+    }
+    let current_time = Utc::now().timestamp_millis();
+    for (archetype, h1emu_ent, pos, hungry, mut state) in &mut query {
+        let Some(tree) = trees.0.get(&archetype.0) else {
+            continue;
+        };
+        let target = player_query
+            .iter()
+            .min_by(|(a, _), (b, _)| distance_2d(pos, a).total_cmp(&distance_2d(pos, b)));
+        tree.tick(&mut TickContext {
+            h1emu_entity: h1emu_ent,
+            position: pos,
+            hungry,
+            target,
+            state: &mut state,
+            current_time,
+        });
+    }
+}
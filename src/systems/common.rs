@@ -1,11 +1,16 @@
 use bevy_ecs::{
+    change_detection::Mut,
     entity::Entity,
-    system::{Commands, Query},
+    system::{Commands, Query, ResMut, SystemParam},
 };
+use chrono::Utc;
+use js_sys::{Array, Float32Array};
 
 use crate::{
-    components::{DespawnCooldown, H1emuEntity, Position},
+    AiEventKind,
+    components::{CharacterId, DespawnCooldown, GroupMember, H1emuEntity, Position, Returning},
     log,
+    ressources::{AiEvent, AiEvents, SpatialIndex},
 };
 
 pub fn is_pos_in_radius(radius: f32, player_pos: &Position, enemi_pos: &Position) -> bool {
@@ -16,8 +21,70 @@ pub fn is_pos_in_radius(radius: f32, player_pos: &Position, enemi_pos: &Position
     (player_x - radius <= enemi_x && enemi_x <= player_x + radius)
         && (player_z - radius <= enemi_z && enemi_z <= player_z + radius)
 }
+pub fn distance_2d(a: &Position, b: &Position) -> f32 {
+    (a.x - b.x).hypot(a.z - b.z)
+}
+pub fn pos_on_ring(center: &Position, angle: f32, distance: f32) -> Position {
+    Position {
+        x: center.x + angle.cos() * distance,
+        y: center.y,
+        z: center.z + angle.sin() * distance,
+    }
+}
+/// Point `distance` away from `from`, in the opposite direction of `threat`
+pub fn pos_away_from(from: &Position, threat: &Position, distance: f32) -> Position {
+    let angle = (from.z - threat.z).atan2(from.x - threat.x);
+    pos_on_ring(from, angle, distance)
+}
+pub fn random_pos_around(center: &Position, radius: f32) -> Position {
+    let angle = js_sys::Math::random() as f32 * std::f32::consts::TAU;
+    let distance = js_sys::Math::random() as f32 * radius;
+    pos_on_ring(center, angle, distance)
+}
+/// Arguments for H1emuEntity::go_to
+pub fn go_to_args(pos: &Position) -> Array {
+    let args = Array::new();
+    let js_pos = Float32Array::from(&[pos.x, pos.y, pos.z][..]);
+    args.push(&js_pos);
+    args
+}
+/// Renumbers the slots of a pack or herd 0..n keeping their order so no two members share
+/// a place and the slots don't keep growing as members come and go
+pub fn compact_slots<'a, T: GroupMember>(members: impl Iterator<Item = Mut<'a, T>>) {
+    let mut members: Vec<_> = members.collect();
+    members.sort_by_key(|member| member.slot());
+    for (slot, member) in members.iter_mut().enumerate() {
+        let slot = slot as u8;
+        if member.slot() != slot {
+            member.set_slot(slot);
+        }
+    }
+}
+
+/// Records AI events along with the character ids as they are when it happens
+#[derive(SystemParam)]
+pub struct AiEventWriter<'w, 's> {
+    events: ResMut<'w, AiEvents>,
+    character_ids: Query<'w, 's, &'static CharacterId>,
+}
+impl AiEventWriter<'_, '_> {
+    pub fn push(&mut self, kind: AiEventKind, entity: Entity, target: Option<Entity>) {
+        let character_id_of = |e| self.character_ids.get(e).ok().map(|id| id.0.clone());
+        let event = AiEvent {
+            kind,
+            entity,
+            character_id: character_id_of(entity),
+            target,
+            target_character_id: target.and_then(character_id_of),
+            time: Utc::now().timestamp_millis(),
+        };
+        self.events.push(event);
+    }
+}
+
 pub fn despawn_inactive(
     query: Query<(Entity, &H1emuEntity, &DespawnCooldown)>,
+    mut events: AiEventWriter,
     mut commands: Commands,
 ) {
     for (e, h, cooldown) in query {
@@ -26,7 +93,24 @@ pub fn despawn_inactive(
             h.destroy();
             if let Ok(mut e_cmds) = commands.get_entity(e) {
                 e_cmds.despawn();
+                events.push(AiEventKind::Despawned, e, None);
             }
         }
     }
 }
+
+pub fn returning_sys(query: Query<(Entity, &Position, &Returning)>, mut commands: Commands) {
+    let current_time = Utc::now().timestamp_millis();
+    for (e, pos, returning) in query {
+        if returning.until <= current_time || distance_2d(pos, &returning.destination) <= 2.0 {
+            commands.entity(e).remove::<Returning>();
+        }
+    }
+}
+
+pub fn spatial_index_sys(query: Query<(Entity, &Position)>, mut index: ResMut<SpatialIndex>) {
+    index.clear();
+    for (e, pos) in query {
+        index.insert(e, pos);
+    }
+}
@@ -0,0 +1,101 @@
+use bevy_ecs::prelude::*;
+use chrono::Utc;
+use wasm_bindgen::JsValue;
+
+use crate::{
+    AiEventKind,
+    components::{
+        Alive, BearEntity, BehaviorTreeState, Charging, Coward, Damaged, Fleeing, H1emuEntity,
+        Investigating, PackMember, Position, Retreating, ThreatTable, Warning, ZombieEntity,
+    },
+    ressources::Packs,
+    systems::{AiEventWriter, BEAR_RAGE_DURATION},
+};
+
+const DAMAGE_FLEE_DURATION: i64 = 30_000;
+
+/// Reactions to the hits reported through AiManager::on_damaged
+#[allow(clippy::type_complexity)]
+pub fn damage_reaction_sys(
+    mut query: Query<
+        (
+            Entity,
+            &H1emuEntity,
+            &Position,
+            Ref<Damaged>,
+            Option<&mut ThreatTable>,
+            Option<&PackMember>,
+            Has<ZombieEntity>,
+            Has<Coward>,
+            Has<BearEntity>,
+            Has<Retreating>,
+        ),
+        (With<Alive>, Without<BehaviorTreeState>),
+    >,
+    attacker_query: Query<&Position, With<Alive>>,
+    mut packs: ResMut<Packs>,
+    mut events: AiEventWriter,
+    mut commands: Commands,
+) {
+    let current_time = Utc::now().timestamp_millis();
+    for (
+        ent,
+        h1emu_ent,
+        pos,
+        damaged,
+        threat_table,
+        pack_member,
+        is_zombie,
+        is_coward,
+        is_bear,
+        retreating,
+    ) in &mut query
+    {
+        if !damaged.is_changed() {
+            continue;
+        }
+        let attacker = damaged
+            .attacker
+            .and_then(|attacker| attacker_query.get(attacker).ok().map(|pos| (attacker, pos)));
+        if is_coward {
+            let from = attacker.map_or(*pos, |(_, attacker_pos)| *attacker_pos);
+            commands
+                .entity(ent)
+                .insert(Fleeing::new(from, DAMAGE_FLEE_DURATION));
+        }
+        let Some((attacker, _)) = attacker else {
+            continue;
+        };
+        if is_zombie {
+            // retaliate right away instead of waiting for the threat to win the hysteresis
+            if let Some(mut threat_table) = threat_table
+                && threat_table.current != Some(attacker)
+            {
+                if let Some(previous_target) = threat_table.current {
+                    events.push(AiEventKind::Deaggro, ent, Some(previous_target));
+                }
+                threat_table.current = Some(attacker);
+                events.push(AiEventKind::Aggro, ent, Some(attacker));
+            }
+            commands.entity(ent).remove::<Investigating>();
+        }
+        if is_bear {
+            let args = js_sys::Array::new();
+            args.push(&JsValue::from_str("Roar"));
+            h1emu_ent.play_animation(&args);
+            let mut e_cmds = commands.entity(ent);
+            e_cmds.remove::<Warning>();
+            e_cmds.insert(Charging {
+                target: attacker,
+                next_order: 0,
+                enraged_until: current_time + BEAR_RAGE_DURATION,
+            });
+        }
+        if let Some(pack_member) = pack_member
+            && !retreating
+            && let Some(pack) = packs.groups.get_mut(&pack_member.pack)
+        {
+            pack.target = Some(attacker);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use bevy_ecs::prelude::*;
+use chrono::Utc;
+
+use crate::{
+    components::{Alive, BehaviorTreeState, Fleeing, H1emuEntity, HerdMember, Position},
+    ressources::Herds,
+    systems::common::{
+        compact_slots, distance_2d, go_to_args, pos_away_from, pos_on_ring, random_pos_around,
+    },
+};
+
+const FLEE_DISTANCE: f32 = 40.0;
+pub const HERD_JOIN_RADIUS: f32 = 20.0;
+const HERD_WANDER_RADIUS: f32 = 20.0;
+const HERD_WANDER_INTERVAL: i64 = 20_000;
+const HERD_FOLLOW_INTERVAL: i64 = 2_000;
+/// Distance between the rings of followers around the leader
+const HERD_SPACING: f32 = 3.0;
+const HERD_SLOTS_PER_RING: u8 = 6;
+const HERD_SLOT_TOLERANCE: f32 = 1.5;
+
+pub fn flee_sys(
+    mut query: Query<(Entity, &H1emuEntity, &Position, &mut Fleeing), With<Alive>>,
+    mut commands: Commands,
+) {
+    let current_time = Utc::now().timestamp_millis();
+    for (ent, h1emu_ent, pos, mut fleeing) in &mut query {
+        if fleeing.until <= current_time {
+            commands.entity(ent).remove::<Fleeing>();
+            continue;
+        }
+        if !fleeing.ordered {
+            h1emu_ent.go_to(&go_to_args(&pos_away_from(
+                pos,
+                &fleeing.from,
+                FLEE_DISTANCE,
+            )));
+            fleeing.ordered = true;
+        }
+    }
+}
+
+/// The leader wanders, followers keep their slot around it
+#[allow(clippy::type_complexity)]
+pub fn herd_sys(
+    mut member_query: Query<
+        (
+            Entity,
+            &H1emuEntity,
+            &Position,
+            &mut HerdMember,
+            Has<Fleeing>,
+            Has<BehaviorTreeState>,
+        ),
+        With<Alive>,
+    >,
+    mut herds: ResMut<Herds>,
+) {
+    let current_time = Utc::now().timestamp_millis();
+    let mut members: HashMap<u32, Vec<(Entity, Position)>> = HashMap::new();
+    for (ent, _, pos, member, ..) in &member_query {
+        members.entry(member.herd).or_default().push((ent, *pos));
+    }
+    herds
+        .groups
+        .retain(|herd_id, _| members.contains_key(herd_id));
+    let mut shrunk = Vec::new();
+    for (herd_id, herd) in herds.groups.iter_mut() {
+        let alive = &members[herd_id];
+        let size = alive.len().min(u8::MAX as usize) as u8;
+        if herd.size != size {
+            // someone died or got removed, close the gap in the slots
+            herd.size = size;
+            shrunk.push(*herd_id);
+        }
+        if herd
+            .leader
+            .is_none_or(|leader| !alive.iter().any(|(ent, _)| *ent == leader))
+        {
+            herd.leader = alive.first().map(|(ent, _)| *ent);
+        }
+    }
+
+    for herd_id in shrunk {
+        compact_slots(
+            member_query
+                .iter_mut()
+                .map(|(_, _, _, member, ..)| member)
+                .filter(|member| member.herd == herd_id),
+        );
+    }
+
+    for (ent, h1emu_ent, pos, mut member, fleeing, tree_driven) in &mut member_query {
+        if fleeing || tree_driven {
+            continue;
+        }
+        let Some(herd) = herds.groups.get_mut(&member.herd) else {
+            continue;
+        };
+        let Some(leader) = herd.leader else {
+            continue;
+        };
+        if leader == ent {
+            if herd.next_move <= current_time {
+                h1emu_ent.go_to(&go_to_args(&random_pos_around(pos, HERD_WANDER_RADIUS)));
+                herd.next_move = current_time + HERD_WANDER_INTERVAL;
+            }
+            continue;
+        }
+        if member.next_order > current_time {
+            continue;
+        }
+        member.next_order = current_time + HERD_FOLLOW_INTERVAL;
+        let Some((_, leader_pos)) = members[&member.herd]
+            .iter()
+            .find(|(other, _)| *other == leader)
+        else {
+            continue;
+        };
+        // one slot per follower on rings around the leader keeps them together but apart
+        let ring = 1 + member.slot / HERD_SLOTS_PER_RING;
+        let angle = (member.slot % HERD_SLOTS_PER_RING) as f32 * std::f32::consts::TAU
+            / HERD_SLOTS_PER_RING as f32
+            + ring as f32 * 0.5;
+        let slot_pos = pos_on_ring(leader_pos, angle, ring as f32 * HERD_SPACING);
+        if distance_2d(pos, &slot_pos) > HERD_SLOT_TOLERANCE {
+            h1emu_ent.go_to(&go_to_args(&slot_pos));
+        }
+    }
+}
+
+/// A spooked member takes the whole herd with it
+#[allow(clippy::type_complexity)]
+pub fn herd_flee_sys(
+    spooked_query: Query<(&HerdMember, &Fleeing), Added<Fleeing>>,
+    calm_query: Query<
+        (Entity, &HerdMember),
+        (With<Alive>, Without<Fleeing>, Without<BehaviorTreeState>),
+    >,
+    mut commands: Commands,
+) {
+    let spooked: HashMap<u32, (Position, i64)> = spooked_query
+        .iter()
+        .map(|(member, fleeing)| (member.herd, (fleeing.from, fleeing.until)))
+        .collect();
+    if spooked.is_empty() {
+        return;
+    }
+    for (ent, member) in &calm_query {
+        if let Some(&(from, until)) = spooked.get(&member.herd) {
+            commands.entity(ent).insert(Fleeing {
+                from,
+                until,
+                ordered: false,
+            });
+        }
+    }
+}
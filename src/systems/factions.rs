@@ -0,0 +1,65 @@
+use bevy_ecs::prelude::*;
+
+use crate::{
+    Relationship,
+    components::{
+        Alive, Archetype, BehaviorTreeState, Faction, Fleeing, Orientation, PlayerEntity, Position,
+    },
+    ressources::{Factions, SpatialIndex},
+    systems::{Perception, common::distance_2d},
+};
+
+const FEAR_RADIUS: f32 = 10.0;
+const FEAR_FLEE_DURATION: i64 = 10_000;
+
+/// Flee from the closest perceived member of a faction this one is afraid of
+#[allow(clippy::type_complexity)]
+pub fn fear_sys(
+    query: Query<
+        (
+            Entity,
+            &Position,
+            &Faction,
+            &Archetype,
+            Option<&Orientation>,
+        ),
+        (
+            With<Alive>,
+            Without<Fleeing>,
+            Without<PlayerEntity>,
+            Without<BehaviorTreeState>,
+        ),
+    >,
+    others_query: Query<(&Position, &Faction), With<Alive>>,
+    factions: Res<Factions>,
+    spatial_index: Res<SpatialIndex>,
+    perception: Perception,
+    mut commands: Commands,
+) {
+    for (ent, pos, faction, archetype, orientation) in &query {
+        if !factions.is_afraid_of_any(faction.0) {
+            continue;
+        }
+        let range = perception
+            .vision(archetype)
+            .map_or(FEAR_RADIUS, |vision| vision.distance);
+        let threat = spatial_index
+            .near(pos, range)
+            .filter(|&other| other != ent)
+            .filter_map(|other| others_query.get(other).ok())
+            .filter(|(_, other_faction)| {
+                factions.relationship(faction.0, other_faction.0) == Relationship::Afraid
+            })
+            .filter(|(other_pos, _)| {
+                perception
+                    .can_see(archetype, pos, orientation, other_pos)
+                    .unwrap_or_else(|| distance_2d(pos, other_pos) <= FEAR_RADIUS)
+            })
+            .min_by(|(a, _), (b, _)| distance_2d(pos, a).total_cmp(&distance_2d(pos, b)));
+        if let Some((threat_pos, _)) = threat {
+            commands
+                .entity(ent)
+                .insert(Fleeing::new(*threat_pos, FEAR_FLEE_DURATION));
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use bevy_ecs::prelude::*;
+
+use crate::{
+    components::{
+        Alive, BehaviorTreeState, Chasing, HordeSlot, IsAttacking, PackMember, Position,
+        ZombieEntity,
+    },
+    systems::common::{distance_2d, pos_on_ring},
+};
+
+/// Zombies hitting the same target at once, the others wait around it
+const MAX_ATTACKERS: usize = 4;
+const ATTACK_SLOT_RADIUS: f32 = 1.0;
+const WAIT_RADIUS: f32 = 4.0;
+/// Closer than that zombies of the same horde push each other away
+const SEPARATION_RADIUS: f32 = 1.5;
+/// Share of the way to the horde centroid added to each destination
+const COHESION_WEIGHT: f32 = 0.2;
+
+/// Spreads zombies going for the same target: the closest get an attack slot around it,
+/// the rest keep a ring further out, all of them staying together without stacking
+#[allow(clippy::type_complexity)]
+pub fn horde_sys(
+    zombie_query: Query<
+        (
+            Entity,
+            &Position,
+            Option<&Chasing>,
+            Option<&IsAttacking>,
+            Option<&HordeSlot>,
+        ),
+        (
+            With<ZombieEntity>,
+            With<Alive>,
+            Without<PackMember>,
+            Without<BehaviorTreeState>,
+        ),
+    >,
+    target_query: Query<&Position, With<Alive>>,
+    mut commands: Commands,
+) {
+    let mut hordes: HashMap<Entity, Vec<(Entity, Position, bool, Option<usize>)>> = HashMap::new();
+    for (ent, pos, chasing, attacking, slot) in &zombie_query {
+        let target = attacking
+            .map(|attacking| attacking.target)
+            .or(chasing.map(|chasing| chasing.target));
+        let Some(target) = target else {
+            if slot.is_some() {
+                commands.entity(ent).remove::<HordeSlot>();
+            }
+            continue;
+        };
+        let previous_slot = slot
+            .filter(|slot| slot.target == target)
+            .and_then(|slot| slot.attack_slot);
+        hordes
+            .entry(target)
+            .or_default()
+            .push((ent, *pos, attacking.is_some(), previous_slot));
+    }
+
+    for (target, mut horde) in hordes {
+        let Ok(target_pos) = target_query.get(target) else {
+            continue;
+        };
+        // whoever is already swinging or holding a slot keeps it, then the closest
+        horde.sort_by(|a, b| {
+            b.2.cmp(&a.2)
+                .then(b.3.is_some().cmp(&a.3.is_some()))
+                .then(distance_2d(&a.1, target_pos).total_cmp(&distance_2d(&b.1, target_pos)))
+        });
+        let count = horde.len() as f32;
+        let centroid = horde
+            .iter()
+            .fold(Position::default(), |mut sum, (_, pos, _, _)| {
+                sum.x += pos.x / count;
+                sum.z += pos.z / count;
+                sum
+            });
+        let mut taken = [false; MAX_ATTACKERS];
+        for (index, &(ent, pos, _, previous_slot)) in horde.iter().enumerate() {
+            let bearing = (pos.z - target_pos.z).atan2(pos.x - target_pos.x);
+            let attack_slot = (index < MAX_ATTACKERS).then(|| {
+                let slot = previous_slot
+                    .filter(|&slot| !taken[slot])
+                    .unwrap_or_else(|| {
+                        (0..MAX_ATTACKERS)
+                            .filter(|&slot| !taken[slot])
+                            .min_by(|&a, &b| {
+                                angle_between(slot_angle(a), bearing)
+                                    .total_cmp(&angle_between(slot_angle(b), bearing))
+                            })
+                            .unwrap_or_default()
+                    });
+                taken[slot] = true;
+                slot
+            });
+            let mut destination = match attack_slot {
+                Some(slot) => pos_on_ring(target_pos, slot_angle(slot), ATTACK_SLOT_RADIUS),
+                None => pos_on_ring(target_pos, bearing, WAIT_RADIUS),
+            };
+            destination.x += (centroid.x - destination.x) * COHESION_WEIGHT;
+            destination.z += (centroid.z - destination.z) * COHESION_WEIGHT;
+            for &(other, other_pos, _, _) in &horde {
+                let distance = distance_2d(&pos, &other_pos);
+                if other == ent || distance >= SEPARATION_RADIUS {
+                    continue;
+                }
+                let push = pos_on_ring(
+                    &Position::default(),
+                    (pos.z - other_pos.z).atan2(pos.x - other_pos.x),
+                    SEPARATION_RADIUS - distance,
+                );
+                destination.x += push.x;
+                destination.z += push.z;
+            }
+            commands.entity(ent).insert(HordeSlot {
+                target,
+                destination,
+                attack_slot,
+            });
+        }
+    }
+}
+
+fn slot_angle(slot: usize) -> f32 {
+    slot as f32 * std::f32::consts::TAU / MAX_ATTACKERS as f32
+}
+
+fn angle_between(a: f32, b: f32) -> f32 {
+    let diff = (a - b).rem_euclid(std::f32::consts::TAU);
+    diff.min(std::f32::consts::TAU - diff)
+}
@@ -0,0 +1,149 @@
+use bevy_ecs::prelude::*;
+use chrono::Utc;
+use wasm_bindgen::JsValue;
+
+use crate::{
+    AiEventKind, Relationship,
+    components::{
+        Alive, Archetype, BehaviorTreeState, Carcass, Carnivore, Charging, Chasing, Coward, Dead,
+        Eating, Faction, Fleeing, H1emuEntity, HungerLevel, Hungry, Hunting, IsAttacking,
+        Orientation, Position, Retreating,
+    },
+    ressources::{Factions, HungerConfig, SpatialIndex},
+    systems::{
+        ATTACK_RANGE, Perception,
+        common::{AiEventWriter, distance_2d, go_to_args},
+    },
+};
+
+const HUNT_RADIUS: f32 = 40.0;
+/// Beyond that the predator closes in slowly to stay unnoticed
+const STALK_DISTANCE: f32 = 12.0;
+const STALK_ORDER_INTERVAL: i64 = 2_000;
+const HUNT_ORDER_INTERVAL: i64 = 500;
+
+/// Hungry carnivores stalk, chase and kill animals of a prey faction then walk to the carcass
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn hunt_sys(
+    mut predator_query: Query<
+        (
+            Entity,
+            &H1emuEntity,
+            &Position,
+            &Faction,
+            &Archetype,
+            Option<&Orientation>,
+            Has<Hungry>,
+            Option<&mut Hunting>,
+        ),
+        (
+            With<Carnivore>,
+            With<HungerLevel>,
+            With<Alive>,
+            Without<Eating>,
+            Without<IsAttacking>,
+            Without<Chasing>,
+            Without<Charging>,
+            Without<Retreating>,
+            Without<Fleeing>,
+            Without<BehaviorTreeState>,
+        ),
+    >,
+    prey_query: Query<(&Position, &Faction), (With<Coward>, With<Alive>)>,
+    carcass_query: Query<&Position, (With<Dead>, With<Carcass>)>,
+    factions: Res<Factions>,
+    hunger_config: Res<HungerConfig>,
+    spatial_index: Res<SpatialIndex>,
+    perception: Perception,
+    mut events: AiEventWriter,
+    mut commands: Commands,
+) {
+    let current_time = Utc::now().timestamp_millis();
+    for (ent, h1emu_ent, pos, faction, archetype, orientation, hungry, hunting) in
+        &mut predator_query
+    {
+        if let Some(hunting) = &hunting
+            && prey_query.get(hunting.prey).is_err()
+        {
+            // the kill is done, eating starts once next to the carcass
+            if let Ok(carcass_pos) = carcass_query.get(hunting.prey) {
+                h1emu_ent.go_to(&go_to_args(carcass_pos));
+            }
+            events.push(AiEventKind::Deaggro, ent, Some(hunting.prey));
+            commands.entity(ent).remove::<Hunting>();
+            continue;
+        }
+        if !hungry {
+            if let Some(hunting) = hunting {
+                events.push(AiEventKind::Deaggro, ent, Some(hunting.prey));
+                commands.entity(ent).remove::<Hunting>();
+            }
+            continue;
+        }
+        let prey = match &hunting {
+            Some(hunting) => prey_query
+                .get(hunting.prey)
+                .ok()
+                .map(|(prey_pos, _)| (hunting.prey, prey_pos)),
+            None => spatial_index
+                .near(pos, HUNT_RADIUS)
+                .filter_map(|other| {
+                    prey_query
+                        .get(other)
+                        .ok()
+                        .map(|(prey_pos, prey_faction)| (other, prey_pos, prey_faction))
+                })
+                .filter(|(_, _, prey_faction)| {
+                    factions.relationship(faction.0, prey_faction.0) == Relationship::Prey
+                })
+                .filter(|(_, prey_pos, _)| {
+                    perception
+                        .can_see(archetype, pos, orientation, prey_pos)
+                        .unwrap_or_else(|| distance_2d(pos, prey_pos) <= HUNT_RADIUS)
+                })
+                .min_by(|(_, a, _), (_, b, _)| distance_2d(pos, a).total_cmp(&distance_2d(pos, b)))
+                .map(|(prey, prey_pos, _)| (prey, prey_pos)),
+        };
+        let Some((prey, prey_pos)) = prey else {
+            continue;
+        };
+        let distance = distance_2d(pos, prey_pos);
+        if distance > HUNT_RADIUS {
+            events.push(AiEventKind::Deaggro, ent, Some(prey));
+            commands.entity(ent).remove::<Hunting>();
+            continue;
+        }
+        if distance <= ATTACK_RANGE {
+            let args = js_sys::Array::new();
+            args.push(&JsValue::from_str("KnifeSlash"));
+            h1emu_ent.play_animation(&args);
+            commands.entity(ent).insert(IsAttacking {
+                target: prey,
+                time_to_hit: current_time + hunger_config.attack_delay(true),
+            });
+            events.push(AiEventKind::AttackStarted, ent, Some(prey));
+            continue;
+        }
+        let interval = if distance > STALK_DISTANCE {
+            STALK_ORDER_INTERVAL
+        } else {
+            HUNT_ORDER_INTERVAL
+        };
+        match hunting {
+            Some(mut hunting) => {
+                if hunting.next_order <= current_time {
+                    h1emu_ent.go_to(&go_to_args(prey_pos));
+                    hunting.next_order = current_time + interval;
+                }
+            }
+            None => {
+                h1emu_ent.go_to(&go_to_args(prey_pos));
+                commands.entity(ent).insert(Hunting {
+                    prey,
+                    next_order: current_time + interval,
+                });
+                events.push(AiEventKind::Aggro, ent, Some(prey));
+            }
+        }
+    }
+}
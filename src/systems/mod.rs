@@ -6,3 +6,36 @@ pub use common::*;
 
 mod zombies;
 pub use zombies::*;
+
+mod wolves;
+pub use wolves::*;
+
+mod bears;
+pub use bears::*;
+
+mod screamers;
+pub use screamers::*;
+
+mod perception;
+pub use perception::*;
+
+mod deer;
+pub use deer::*;
+
+mod damage;
+pub use damage::*;
+
+mod behavior_tree;
+pub use behavior_tree::*;
+
+mod state;
+pub use state::*;
+
+mod factions;
+pub use factions::*;
+
+mod hunting;
+pub use hunting::*;
+
+mod horde;
+pub use horde::*;
@@ -0,0 +1,247 @@
+use bevy_ecs::{prelude::*, system::SystemParam};
+use chrono::Utc;
+use js_sys::Float32Array;
+use wasm_bindgen::JsValue;
+
+use crate::{
+    components::{
+        Alive, Archetype, BehaviorTreeState, Charging, Chasing, Coward, Eating, Fleeing,
+        H1emuEntity, Investigating, IsAttacking, Orientation, PlayerEntity, Position, Territory,
+        ThreatTable, Warning, ZombieEntity,
+    },
+    error,
+    ressources::{LineOfSight, Noises, SpatialIndex, Vision, VisionConfig},
+    systems::common::distance_2d,
+};
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn hearing_sys(
+    mut noises: ResMut<Noises>,
+    zombie_query: Query<
+        (Entity, &Position),
+        (
+            With<ZombieEntity>,
+            With<Alive>,
+            Without<IsAttacking>,
+            Without<Chasing>,
+            Without<Eating>,
+            Without<BehaviorTreeState>,
+        ),
+    >,
+    coward_query: Query<
+        (Entity, &Position),
+        (With<Coward>, With<Alive>, Without<BehaviorTreeState>),
+    >,
+    bear_query: Query<
+        (Entity, &H1emuEntity, &Position),
+        (
+            With<Territory>,
+            With<Alive>,
+            Without<Charging>,
+            Without<BehaviorTreeState>,
+        ),
+    >,
+    player_query: Query<&Position, (With<PlayerEntity>, With<Alive>)>,
+    mut threat_query: Query<(Entity, &Position, &mut ThreatTable), With<Alive>>,
+    spatial_index: Res<SpatialIndex>,
+    mut commands: Commands,
+) {
+    let current_time = Utc::now().timestamp_millis();
+    for noise in noises.0.drain(..) {
+        let duration = noise.kind.reaction_duration();
+        if let Some(source) = noise.source {
+            for (ent, pos, mut threat_table) in &mut threat_query {
+                // its own noise doesn't make it a threat to itself
+                if ent != source && distance_2d(pos, &noise.position) <= noise.radius {
+                    threat_table.add_noise(source, noise.kind.threat(), current_time);
+                }
+            }
+        }
+        for (ent, pos) in spatial_index
+            .near(&noise.position, noise.radius)
+            .filter_map(|e| zombie_query.get(e).ok())
+        {
+            if distance_2d(pos, &noise.position) <= noise.radius {
+                commands
+                    .entity(ent)
+                    .insert(Investigating::new(noise.position, duration));
+            }
+        }
+        for (ent, pos) in &coward_query {
+            if distance_2d(pos, &noise.position) <= noise.radius {
+                commands
+                    .entity(ent)
+                    .insert(Fleeing::new(noise.position, duration));
+            }
+        }
+        for (ent, h1emu_ent, pos) in &bear_query {
+            if distance_2d(pos, &noise.position) > noise.radius {
+                continue;
+            }
+            let args = js_sys::Array::new();
+            args.push(&JsValue::from_str("Roar"));
+            h1emu_ent.play_animation(&args);
+            // a known player making noise counts as already warned
+            let source = noise
+                .source
+                .and_then(|source| player_query.get(source).ok().map(|pos| (source, pos)));
+            if let Some((source, source_pos)) = source {
+                commands.entity(ent).insert(Warning {
+                    target: source,
+                    distance: distance_2d(pos, source_pos),
+                });
+            }
+        }
+    }
+}
+
+#[derive(SystemParam)]
+pub struct Perception<'w> {
+    vision_config: Res<'w, VisionConfig>,
+    line_of_sight: NonSend<'w, LineOfSight>,
+}
+impl Perception<'_> {
+    pub fn vision(&self, archetype: &Archetype) -> Option<&Vision> {
+        self.vision_config.0.get(&archetype.0)
+    }
+    /// View cone then host line of sight, None when the archetype has no vision configured
+    pub fn can_see(
+        &self,
+        archetype: &Archetype,
+        pos: &Position,
+        orientation: Option<&Orientation>,
+        target_pos: &Position,
+    ) -> Option<bool> {
+        let vision = self.vision(archetype)?;
+        Some(
+            is_in_view_cone(vision, pos, orientation, target_pos)
+                && self.has_line_of_sight(pos, target_pos),
+        )
+    }
+    pub fn has_line_of_sight(&self, from: &Position, to: &Position) -> bool {
+        let Some(line_of_sight) = &self.line_of_sight.0 else {
+            return true;
+        };
+        let from = Float32Array::from(&[from.x, from.y, from.z][..]);
+        let to = Float32Array::from(&[to.x, to.y, to.z][..]);
+        match line_of_sight.call2(&JsValue::NULL, &from, &to) {
+            Ok(result) => result.is_truthy(),
+            Err(err) => {
+                error!(format!("{:?}", err));
+                true
+            }
+        }
+    }
+}
+
+pub fn is_in_view_cone(
+    vision: &Vision,
+    pos: &Position,
+    orientation: Option<&Orientation>,
+    target_pos: &Position,
+) -> bool {
+    if distance_2d(pos, target_pos) > vision.distance {
+        return false;
+    }
+    let Some(orientation) = orientation else {
+        return true;
+    };
+    let to_target = (target_pos.x - pos.x).atan2(target_pos.z - pos.z);
+    let mut delta = (to_target - orientation.0).rem_euclid(std::f32::consts::TAU);
+    if delta > std::f32::consts::PI {
+        delta = std::f32::consts::TAU - delta;
+    }
+    delta <= vision.fov / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
+    use super::*;
+
+    const VISION: Vision = Vision {
+        distance: 10.0,
+        fov: FRAC_PI_2,
+    };
+
+    fn at(x: f32, z: f32) -> Position {
+        Position { x, y: 0.0, z }
+    }
+
+    #[test]
+    fn view_cone_is_limited_by_distance() {
+        let origin = at(0.0, 0.0);
+        assert!(is_in_view_cone(&VISION, &origin, None, &at(0.0, 10.0)));
+        assert!(!is_in_view_cone(&VISION, &origin, None, &at(0.0, 10.01)));
+        // without an orientation it sees all around
+        assert!(is_in_view_cone(&VISION, &origin, None, &at(0.0, -5.0)));
+    }
+
+    #[test]
+    fn view_cone_edges() {
+        let origin = at(0.0, 0.0);
+        let facing_z = Orientation(0.0);
+        let inside = at(
+            (FRAC_PI_4 - 0.01).sin() * 5.0,
+            (FRAC_PI_4 - 0.01).cos() * 5.0,
+        );
+        let outside = at(
+            (FRAC_PI_4 + 0.01).sin() * 5.0,
+            (FRAC_PI_4 + 0.01).cos() * 5.0,
+        );
+        assert!(is_in_view_cone(&VISION, &origin, Some(&facing_z), &inside));
+        assert!(!is_in_view_cone(
+            &VISION,
+            &origin,
+            Some(&facing_z),
+            &outside
+        ));
+        assert!(!is_in_view_cone(
+            &VISION,
+            &origin,
+            Some(&facing_z),
+            &at(0.0, -5.0)
+        ));
+    }
+
+    #[test]
+    fn view_cone_wraps_around_pi() {
+        let origin = at(0.0, 0.0);
+        // facing -z from either side of the wrap still sees what's behind the origin
+        let behind = at(-0.1, -5.0);
+        assert!(is_in_view_cone(
+            &VISION,
+            &origin,
+            Some(&Orientation(PI)),
+            &behind
+        ));
+        assert!(is_in_view_cone(
+            &VISION,
+            &origin,
+            Some(&Orientation(-PI)),
+            &behind
+        ));
+        assert!(is_in_view_cone(
+            &VISION,
+            &origin,
+            Some(&Orientation(3.0 * PI)),
+            &behind
+        ));
+    }
+
+    #[test]
+    fn full_circle_fov_sees_everything_in_range() {
+        let vision = Vision {
+            distance: 10.0,
+            fov: std::f32::consts::TAU,
+        };
+        let origin = at(0.0, 0.0);
+        assert!(is_in_view_cone(
+            &vision,
+            &origin,
+            Some(&Orientation(0.0)),
+            &at(0.0, -5.0)
+        ));
+    }
+}
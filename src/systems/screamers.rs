@@ -0,0 +1,77 @@
+use bevy_ecs::prelude::*;
+use chrono::Utc;
+use wasm_bindgen::JsValue;
+
+use crate::{
+    components::{
+        Alive, Archetype, BehaviorTreeState, Chasing, Eating, H1emuEntity, Investigating,
+        IsAttacking, Orientation, PlayerEntity, Position, ScreamCooldown, ZombieEntity,
+    },
+    log,
+    ressources::SpatialIndex,
+    systems::{Perception, common::distance_2d},
+};
+
+pub const SCREAM_COOLDOWN: i64 = 30_000;
+const SCREAMER_SIGHT_RADIUS: f32 = 15.0;
+const SCREAM_ALERT_RADIUS: f32 = 60.0;
+pub const INVESTIGATION_DURATION: i64 = 30_000;
+
+#[allow(clippy::type_complexity)]
+pub fn screamer_sys(
+    mut screamer_query: Query<
+        (
+            Entity,
+            &H1emuEntity,
+            &Position,
+            &mut ScreamCooldown,
+            &Archetype,
+            Option<&Orientation>,
+        ),
+        (With<Alive>, Without<BehaviorTreeState>),
+    >,
+    player_query: Query<&Position, (With<PlayerEntity>, With<Alive>)>,
+    zombie_query: Query<
+        (Entity, &Position),
+        (
+            With<ZombieEntity>,
+            With<Alive>,
+            Without<IsAttacking>,
+            Without<Chasing>,
+            Without<Eating>,
+            Without<BehaviorTreeState>,
+        ),
+    >,
+    perception: Perception,
+    spatial_index: Res<SpatialIndex>,
+    mut commands: Commands,
+) {
+    for (screamer_ent, h1emu_ent, pos, mut cooldown, archetype, orientation) in &mut screamer_query
+    {
+        if cooldown.is_in_cooldown() {
+            continue;
+        }
+        let Some(player_pos) = player_query.iter().find(|player_pos| {
+            perception
+                .can_see(archetype, pos, orientation, player_pos)
+                .unwrap_or_else(|| distance_2d(pos, player_pos) <= SCREAMER_SIGHT_RADIUS)
+        }) else {
+            continue;
+        };
+        log!("scream");
+        let args = js_sys::Array::new();
+        args.push(&JsValue::from_str("Scream"));
+        h1emu_ent.play_animation(&args);
+        cooldown.last_scream = Utc::now().timestamp_millis();
+        for (zombie_ent, zombie_pos) in spatial_index
+            .near(pos, SCREAM_ALERT_RADIUS)
+            .filter_map(|e| zombie_query.get(e).ok())
+        {
+            if zombie_ent != screamer_ent && distance_2d(pos, zombie_pos) <= SCREAM_ALERT_RADIUS {
+                commands
+                    .entity(zombie_ent)
+                    .insert(Investigating::new(*player_pos, INVESTIGATION_DURATION));
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use bevy_ecs::prelude::*;
+use chrono::Utc;
+
+use crate::{
+    AiStateKind,
+    components::{
+        AiState, Charging, Chasing, Eating, Flanking, Fleeing, Hunting, Investigating, IsAttacking,
+        Retreating, Returning, Roaming,
+    },
+    ressources::{StateTransition, StateTransitions},
+};
+
+/// Derives the state from the behavior components and records every transition
+#[allow(clippy::type_complexity)]
+pub fn ai_state_sys(
+    mut query: Query<(
+        Entity,
+        &mut AiState,
+        Has<Fleeing>,
+        Has<Retreating>,
+        Has<IsAttacking>,
+        Has<Eating>,
+        Has<Chasing>,
+        Has<Hunting>,
+        Has<Charging>,
+        Has<Flanking>,
+        Has<Investigating>,
+        Has<Returning>,
+        Option<&Roaming>,
+    )>,
+    mut transitions: ResMut<StateTransitions>,
+) {
+    let current_time = Utc::now().timestamp_millis();
+    for (
+        ent,
+        mut state,
+        fleeing,
+        retreating,
+        attacking,
+        eating,
+        chasing,
+        hunting,
+        charging,
+        flanking,
+        investigating,
+        returning,
+        roaming,
+    ) in &mut query
+    {
+        let desired = if fleeing || retreating {
+            AiStateKind::Fleeing
+        } else if attacking {
+            AiStateKind::Attacking
+        } else if eating {
+            AiStateKind::Eating
+        } else if chasing || hunting || charging || flanking {
+            AiStateKind::Chasing
+        } else if investigating {
+            AiStateKind::Investigating
+        } else if returning {
+            AiStateKind::Returning
+        } else if roaming.is_some_and(|roaming| roaming.wandering) {
+            AiStateKind::Wandering
+        } else {
+            AiStateKind::Idle
+        };
+        if state.kind == desired {
+            continue;
+        }
+        // a transition the guard rejects keeps the current state until an allowed one comes up
+        let time_in_state = state.time_in_state(current_time);
+        if let Some(from) = state.transition(desired, current_time) {
+            transitions.0.push(StateTransition {
+                entity: ent,
+                from,
+                to: desired,
+                time_in_state,
+            });
+        }
+    }
+}
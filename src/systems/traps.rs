@@ -3,26 +3,34 @@ use chrono::Utc;
 use wasm_bindgen::JsValue;
 
 use crate::{
+    AiEventKind, NoiseKind,
     components::{Alive, CharacterId, DespawnCooldown, H1emuEntity, Position, Trap, TrapsCooldown},
     log,
-    systems::common::is_pos_in_radius,
+    ressources::{Noise, Noises},
+    systems::common::{AiEventWriter, is_pos_in_radius},
 };
 
+const TRAP_NOISE_RADIUS: f32 = 40.0;
+
+#[allow(clippy::type_complexity)]
 pub fn trap_sys(
     mut trap_query: Query<(
+        Entity,
         &Trap,
         &Position,
         &H1emuEntity,
         &mut TrapsCooldown,
         &mut DespawnCooldown,
     )>,
-    mut others_query: Query<(&Position, &CharacterId), (With<Alive>, Changed<Position>)>,
+    mut others_query: Query<(Entity, &Position, &CharacterId), (With<Alive>, Changed<Position>)>,
+    mut noises: ResMut<Noises>,
+    mut events: AiEventWriter,
 ) {
-    for (ent, pos, h1emu_ent, mut cooldown, mut dc) in &mut trap_query {
+    for (trap_ent, ent, pos, h1emu_ent, mut cooldown, mut dc) in &mut trap_query {
         if cooldown.is_in_cooldown() {
             continue;
         }
-        for (other_pos, other_h1emu_ent) in &mut others_query {
+        for (other_ent, other_pos, other_h1emu_ent) in &mut others_query {
             if is_pos_in_radius(ent.0, other_pos, pos) {
                 // TODO: store characterId directly
                 let target_character_id = other_h1emu_ent.0.clone();
@@ -31,6 +39,13 @@ pub fn trap_sys(
                 args.push(&character_id_jsvalue);
                 cooldown.last_trigger = Utc::now().timestamp_millis();
                 h1emu_ent.detonate(&args);
+                events.push(AiEventKind::TrapTriggered, trap_ent, Some(other_ent));
+                noises.0.push(Noise {
+                    position: *pos,
+                    radius: TRAP_NOISE_RADIUS,
+                    kind: NoiseKind::Explosion,
+                    source: None,
+                });
                 log!("register_activity");
                 dc.register_activity();
                 break;
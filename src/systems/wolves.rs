@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+use bevy_ecs::prelude::*;
+use chrono::Utc;
+
+use crate::{
+    components::{
+        Alive, BehaviorTreeState, Chasing, Flanking, H1emuEntity, IsAttacking, PackMember,
+        Position, Retreating,
+    },
+    log,
+    ressources::Packs,
+    systems::common::{compact_slots, distance_2d, go_to_args, pos_away_from, pos_on_ring},
+};
+
+pub const PACK_JOIN_RADIUS: f32 = 20.0;
+const PACK_LEASH_RADIUS: f32 = 40.0;
+const FLANK_DISTANCE: f32 = 4.0;
+const FLANK_ORDER_INTERVAL: i64 = 500;
+const RETREAT_DISTANCE: f32 = 40.0;
+const RETREAT_DURATION: i64 = 20_000;
+
+#[derive(Default)]
+struct PackSnapshot {
+    alive: u8,
+    acquired_target: Option<Entity>,
+    members_pos: Vec<Position>,
+}
+
+/// Shares targets between pack members and makes the pack retreat once it lost half of itself
+#[allow(clippy::type_complexity)]
+pub fn wolf_pack_sys(
+    mut member_query: Query<
+        (
+            Entity,
+            &mut PackMember,
+            &Position,
+            &H1emuEntity,
+            Option<&IsAttacking>,
+            Option<&Chasing>,
+            Has<Retreating>,
+            Has<BehaviorTreeState>,
+        ),
+        With<Alive>,
+    >,
+    target_query: Query<&Position, With<Alive>>,
+    mut packs: ResMut<Packs>,
+    mut commands: Commands,
+) {
+    let current_time = Utc::now().timestamp_millis();
+    let mut snapshots: HashMap<u32, PackSnapshot> = HashMap::new();
+    for (_, member, pos, _, attacking, chasing, ..) in &member_query {
+        let snapshot = snapshots.entry(member.pack).or_default();
+        snapshot.alive = snapshot.alive.saturating_add(1);
+        snapshot.members_pos.push(*pos);
+        if snapshot.acquired_target.is_none() {
+            snapshot.acquired_target = attacking
+                .map(|attacking| attacking.target)
+                .or(chasing.map(|chasing| chasing.target));
+        }
+    }
+    packs
+        .groups
+        .retain(|pack_id, _| snapshots.contains_key(pack_id));
+
+    let mut regrouped = Vec::new();
+    for (pack_id, pack) in packs.groups.iter_mut() {
+        let snapshot = &snapshots[pack_id];
+        if pack.retreat_until > current_time {
+            continue;
+        }
+        if pack.retreat_until != 0 {
+            // regrouped, the survivors are the new full strength
+            pack.retreat_until = 0;
+            pack.size = snapshot.alive;
+            regrouped.push(*pack_id);
+        }
+        if pack.target.is_none() {
+            pack.target = snapshot.acquired_target;
+        }
+        let Some(target) = pack.target else {
+            continue;
+        };
+        let Ok(target_pos) = target_query.get(target) else {
+            pack.target = None;
+            continue;
+        };
+        pack.last_target_pos = Some(*target_pos);
+        let in_leash = snapshot
+            .members_pos
+            .iter()
+            .any(|member_pos| distance_2d(member_pos, target_pos) <= PACK_LEASH_RADIUS);
+        if !in_leash {
+            pack.target = None;
+            continue;
+        }
+        if snapshot.alive * 2 < pack.size {
+            log!("pack retreating");
+            pack.target = None;
+            pack.retreat_until = current_time + RETREAT_DURATION;
+        }
+    }
+
+    for pack_id in regrouped {
+        compact_slots(
+            member_query
+                .iter_mut()
+                .map(|(_, member, ..)| member)
+                .filter(|member| member.pack == pack_id),
+        );
+    }
+
+    for (ent, member, pos, h1emu_ent, _, _, retreating, tree_driven) in &member_query {
+        if retreating || tree_driven {
+            continue;
+        }
+        let Some(pack) = packs.groups.get(&member.pack) else {
+            continue;
+        };
+        if pack.retreat_until <= current_time {
+            continue;
+        }
+        let mut e_cmds = commands.entity(ent);
+        e_cmds.remove::<(IsAttacking, Chasing, Flanking)>();
+        e_cmds.insert(Retreating {
+            until: pack.retreat_until,
+        });
+        if let Some(threat_pos) = pack.last_target_pos {
+            h1emu_ent.go_to(&go_to_args(&pos_away_from(
+                pos,
+                &threat_pos,
+                RETREAT_DISTANCE,
+            )));
+        }
+    }
+}
+
+pub fn end_retreat_sys(query: Query<(Entity, &Retreating)>, mut commands: Commands) {
+    let current_time = Utc::now().timestamp_millis();
+    for (ent, retreating) in &query {
+        if retreating.until <= current_time {
+            commands.entity(ent).remove::<Retreating>();
+        }
+    }
+}
+
+/// Pack members surround the shared target instead of stacking on it
+#[allow(clippy::type_complexity)]
+pub fn wolf_flank_sys(
+    query: Query<
+        (
+            Entity,
+            &H1emuEntity,
+            &Position,
+            &PackMember,
+            Option<&Flanking>,
+        ),
+        (
+            With<Alive>,
+            Without<IsAttacking>,
+            Without<Retreating>,
+            Without<BehaviorTreeState>,
+        ),
+    >,
+    target_query: Query<&Position, With<Alive>>,
+    packs: Res<Packs>,
+    mut commands: Commands,
+) {
+    let current_time = Utc::now().timestamp_millis();
+    for (ent, h1emu_ent, pos, member, flanking) in &query {
+        let Some(pack) = packs.groups.get(&member.pack) else {
+            continue;
+        };
+        let Some(target_pos) = pack.target.and_then(|target| target_query.get(target).ok()) else {
+            if flanking.is_some() {
+                commands.entity(ent).remove::<Flanking>();
+            }
+            continue;
+        };
+        if flanking.is_some_and(|flanking| flanking.next_order > current_time) {
+            continue;
+        }
+        let angle = member.slot as f32 * std::f32::consts::TAU / pack.size.max(1) as f32;
+        let flank_pos = pos_on_ring(target_pos, angle, FLANK_DISTANCE);
+        let destination = if distance_2d(pos, &flank_pos) > 1.0
+            && distance_2d(pos, target_pos) > FLANK_DISTANCE
+        {
+            flank_pos
+        } else {
+            *target_pos
+        };
+        h1emu_ent.go_to(&go_to_args(&destination));
+        commands.entity(ent).insert(Flanking {
+            next_order: current_time + FLANK_ORDER_INTERVAL,
+        });
+    }
+}
@@ -4,15 +4,29 @@ use js_sys::Float32Array;
 use wasm_bindgen::JsValue;
 
 use crate::{
+    AiEventKind, Relationship,
     components::{
-        Alive, Carnivore, CharacterId, Coward, Dead, Eating, H1emuEntity,
-        HostileToPlayer, HungerLevel, Hungry, IsAttacking, PlayerEntity, Position,
-        ZombieEntity,
-    }, log,
-    ressources::HungerTimer,
-    systems::common::is_pos_in_radius,
+        Alive, Archetype, BehaviorTreeState, Carcass, Carnivore, CharacterId, Charging, Chasing,
+        Consumed, Coward, Damaged, Dead, Eating, Faction, Flanking, Fleeing, H1emuEntity,
+        HordeSlot, HungerLevel, Hungry, Hunting, Investigating, IsAttacking, LastMeal, Orientation,
+        PackMember, PlayerEntity, PollAliveness, Position, Retreating, Returning, Roaming,
+        Territory, ThreatTable, ZombieEntity,
+    },
+    error, log,
+    ressources::{
+        AiDamageCallback, CarcassConfig, Factions, FoodValues, HungerConfig, HungerTimer,
+        SpatialIndex,
+    },
+    systems::{
+        Perception,
+        common::{
+            AiEventWriter, distance_2d, go_to_args, is_pos_in_radius, pos_on_ring,
+            random_pos_around,
+        },
+    },
 };
 
+#[allow(dead_code)]
 pub fn test_follow(
     mut zombie_query: Query<&H1emuEntity, With<ZombieEntity>>,
     mut player_query: Query<&H1emuEntity, With<PlayerEntity>>,
@@ -33,40 +47,163 @@ pub fn test_follow(
     }
 }
 
+pub const ATTACK_RANGE: f32 = 1.5;
+const CHASE_ORDER_INTERVAL: i64 = 500;
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 pub fn hostile_to_player_sys(
     mut hostile_query: Query<
-        (&H1emuEntity, &Position, Entity),
-        (With<HostileToPlayer>, Without<IsAttacking>, With<Alive>),
-    >,
-    mut all_positions_query: Query<
-        (Entity, &Position, &H1emuEntity),
-        (With<PlayerEntity>, With<Alive>),
+        (
+            &H1emuEntity,
+            &Position,
+            Entity,
+            Has<Hungry>,
+            Option<&Chasing>,
+            Has<PackMember>,
+            &Archetype,
+            Option<&Orientation>,
+            &mut ThreatTable,
+            &Faction,
+            Option<&HordeSlot>,
+            Has<Coward>,
+        ),
+        (
+            // bears only pick targets while charging
+            Or<(Without<Territory>, With<Charging>)>,
+            Without<Fleeing>,
+            Without<IsAttacking>,
+            Without<Retreating>,
+            Without<BehaviorTreeState>,
+            With<Alive>,
+        ),
     >,
+    targets_query: Query<(Entity, &Position, &Faction), With<Alive>>,
+    hunger_config: Res<HungerConfig>,
+    factions: Res<Factions>,
+    spatial_index: Res<SpatialIndex>,
+    perception: Perception,
+    mut events: AiEventWriter,
     mut commands: Commands,
 ) {
-    for (hostile_h1emu_ent, hostile_pos, hostile_ent) in &mut hostile_query {
-        for (player_ent, player_pos, player_h1emu_ent) in &mut all_positions_query {
-            // let hostile_pos = hostile_ent.get_position();
-            if is_pos_in_radius(1.5, player_pos, hostile_pos) {
-                // Just a quick test nothing fancy but even with 800 entities this run taking only
-                // a microsec probably even less that's crazy
-                let args = js_sys::Array::new();
-                args.push(&JsValue::from_str("KnifeSlash"));
-                hostile_h1emu_ent.play_animation(&args);
-                let mut ec = commands.get_entity(hostile_ent).unwrap();
-                let current_time = Utc::now().timestamp_millis();
-                ec.insert(IsAttacking {
-                    target: player_ent,
-                    time_to_hit: current_time + 1000_i64,
-                });
-                break;
+    let current_time = Utc::now().timestamp_millis();
+    for (
+        hostile_h1emu_ent,
+        hostile_pos,
+        hostile_ent,
+        hungry,
+        chasing,
+        in_pack,
+        archetype,
+        orientation,
+        mut threat_table,
+        faction,
+        horde_slot,
+        is_coward,
+    ) in &mut hostile_query
+    {
+        let aggro_radius = hunger_config.aggro_radius(hungry);
+        let vision = perception.vision(archetype);
+        let range = vision.map_or(aggro_radius, |vision| vision.distance);
+        for (player_ent, player_pos, target_faction) in spatial_index
+            .near(hostile_pos, range)
+            .filter(|&e| e != hostile_ent)
+            .filter_map(|e| targets_query.get(e).ok())
+        {
+            // whoever hurt it stays a target whatever the relationship, cowards only run
+            let retaliating = !is_coward
+                && threat_table
+                    .entries
+                    .get(&player_ent)
+                    .is_some_and(|threat| threat.damage > 0.0);
+            let hostile =
+                factions.relationship(faction.0, target_faction.0) == Relationship::Hostile;
+            if !retaliating && !hostile {
+                continue;
+            }
+            let perceived = match vision {
+                // a chased target stays tracked while in view distance
+                Some(vision) if threat_table.current == Some(player_ent) => {
+                    distance_2d(hostile_pos, player_pos) <= vision.distance
+                }
+                Some(_) => perception
+                    .can_see(archetype, hostile_pos, orientation, player_pos)
+                    .unwrap_or_default(),
+                None => is_pos_in_radius(aggro_radius, player_pos, hostile_pos),
+            };
+            if perceived {
+                threat_table.seen(player_ent, current_time);
+            }
+        }
+        let previous_target = threat_table.current;
+        let retaliating: Vec<Entity> = threat_table
+            .entries
+            .iter()
+            .filter(|(_, threat)| !is_coward && threat.damage > 0.0)
+            .map(|(target, _)| *target)
+            .collect();
+        let target = threat_table
+            .select(current_time, range, |target| {
+                if target == hostile_ent {
+                    return None;
+                }
+                let (_, player_pos, target_faction) = targets_query.get(target).ok()?;
+                let hostile =
+                    factions.relationship(faction.0, target_faction.0) == Relationship::Hostile;
+                (hostile || retaliating.contains(&target))
+                    .then(|| distance_2d(hostile_pos, player_pos))
+            })
+            .and_then(|target| targets_query.get(target).ok());
+        let new_target = target.map(|(player_ent, _, _)| player_ent);
+        if new_target != previous_target {
+            if let Some(previous_target) = previous_target {
+                events.push(AiEventKind::Deaggro, hostile_ent, Some(previous_target));
+            }
+            if let Some(new_target) = new_target {
+                events.push(AiEventKind::Aggro, hostile_ent, Some(new_target));
             }
         }
+        let Some((player_ent, player_pos, _)) = target else {
+            if chasing.is_some() {
+                commands.entity(hostile_ent).remove::<Chasing>();
+            }
+            continue;
+        };
+        // without a slot for that target it's on its own and goes straight in
+        let horde_slot = horde_slot.filter(|slot| slot.target == player_ent);
+        let can_attack = horde_slot.is_none_or(|slot| slot.attack_slot.is_some());
+        let destination = horde_slot.map_or(player_pos, |slot| &slot.destination);
+        if can_attack && is_pos_in_radius(ATTACK_RANGE, player_pos, hostile_pos) {
+            // Just a quick test nothing fancy but even with 800 entities this run taking only
+            // a microsec probably even less that's crazy
+            let args = js_sys::Array::new();
+            args.push(&JsValue::from_str("KnifeSlash"));
+            hostile_h1emu_ent.play_animation(&args);
+            let mut ec = commands.get_entity(hostile_ent).unwrap();
+            ec.insert(IsAttacking {
+                target: player_ent,
+                time_to_hit: current_time + hunger_config.attack_delay(hungry),
+            });
+            events.push(AiEventKind::AttackStarted, hostile_ent, Some(player_ent));
+        } else if chasing.is_none_or(|chasing| {
+            chasing.target != player_ent || chasing.next_order <= current_time
+        }) {
+            // packs move through wolf_flank_sys, the chase only marks the target for them
+            if !in_pack {
+                hostile_h1emu_ent.go_to(&go_to_args(destination));
+            }
+            commands.entity(hostile_ent).insert(Chasing {
+                target: player_ent,
+                next_order: current_time + CHASE_ORDER_INTERVAL,
+            });
+        }
     }
 }
 pub fn attack_hit_sys(
     mut query: Query<(&IsAttacking, Entity, &H1emuEntity, &Position), With<Alive>>,
-    pos_query: Query<(&Position, &CharacterId), With<Alive>>,
+    pos_query: Query<(&Position, &CharacterId, Has<PlayerEntity>), With<Alive>>,
+    attacker_id_query: Query<&CharacterId>,
+    ai_damage: NonSend<AiDamageCallback>,
+    mut events: AiEventWriter,
     mut commands: Commands,
 ) {
     let current_time = Utc::now().timestamp_millis();
@@ -76,41 +213,51 @@ pub fn attack_hit_sys(
         }
         let target_pos = pos_query.get(attack.target);
 
-        if let Ok((target_pos, charid)) = target_pos {
-            if is_pos_in_radius(1.5, attacker_pos, target_pos) {
-                let args = js_sys::Array::new();
+        if let Ok((target_pos, charid, is_player)) = target_pos {
+            if is_pos_in_radius(ATTACK_RANGE, attacker_pos, target_pos) {
                 let character_id_jsvalue: JsValue = charid.0.clone().into();
-                args.push(&character_id_jsvalue);
-                attacker_h1emu_ent.apply_damage(&args);
+                match &ai_damage.0 {
+                    // the host damages players itself, other AI entities go through the hook
+                    Some(callback) if !is_player => {
+                        let attacker_id = attacker_id_query
+                            .get(attack_ent)
+                            .map_or(JsValue::UNDEFINED, |id| id.0.clone().into());
+                        if let Err(err) =
+                            callback.call2(&JsValue::NULL, &attacker_id, &character_id_jsvalue)
+                        {
+                            error!(format!("{:?}", err));
+                        }
+                    }
+                    _ => {
+                        let args = js_sys::Array::new();
+                        args.push(&character_id_jsvalue);
+                        attacker_h1emu_ent.apply_damage(&args);
+                    }
+                }
+                events.push(AiEventKind::AttackHit, attack_ent, Some(attack.target));
+            } else {
+                events.push(AiEventKind::AttackMissed, attack_ent, Some(attack.target));
             }
         } else {
             log!("Failed to get target position, attack canceled");
+            events.push(AiEventKind::AttackMissed, attack_ent, Some(attack.target));
         }
 
         commands.entity(attack_ent).remove::<IsAttacking>();
     }
 }
 
-pub fn coward_sys(
-    mut coward_query: Query<(&H1emuEntity, &Position), (With<Coward>, With<Alive>)>,
-    mut others_query: Query<&Position, (Without<Coward>, With<Alive>, Changed<Position>)>,
-) {
-    for (coward_ent, coward_pos) in &mut coward_query {
-        for other_pos in &mut others_query {
-            if is_pos_in_radius(2.0, other_pos, coward_pos) {
-                log!("i'm afraid");
-                break;
-            }
-        }
-    }
-}
-
 pub fn check_aliveness_sys(
-    mut query: Query<(&H1emuEntity, Entity), With<Alive>>,
+    mut query: Query<(&H1emuEntity, Entity, &mut PollAliveness), With<Alive>>,
 
     mut commands: Commands,
 ) {
-    for (h1emu_ent, ent) in &mut query {
+    let current_time = Utc::now().timestamp_millis();
+    for (h1emu_ent, ent, mut poll) in &mut query {
+        if !poll.is_due(current_time) {
+            continue;
+        }
+        poll.delay(current_time);
         if !h1emu_ent.get_isAlive() {
             commands.entity(ent).remove::<Alive>();
             commands.entity(ent).insert(Dead());
@@ -119,11 +266,16 @@ pub fn check_aliveness_sys(
 }
 
 pub fn check_player_revived_sys(
-    mut query: Query<(&H1emuEntity, Entity), With<Dead>>,
+    mut query: Query<(&H1emuEntity, Entity, &mut PollAliveness), With<Dead>>,
 
     mut commands: Commands,
 ) {
-    for (h1emu_ent, ent) in &mut query {
+    let current_time = Utc::now().timestamp_millis();
+    for (h1emu_ent, ent, mut poll) in &mut query {
+        if !poll.is_due(current_time) {
+            continue;
+        }
+        poll.delay(current_time);
         if h1emu_ent.get_isAlive() {
             commands.entity(ent).remove::<Dead>();
             commands.entity(ent).insert(Alive());
@@ -131,58 +283,204 @@ pub fn check_player_revived_sys(
     }
 }
 
+pub fn carcass_sys(
+    new_dead_query: Query<(Entity, &Archetype), Added<Dead>>,
+    revived_query: Query<Entity, (With<Carcass>, With<Alive>)>,
+    food_values: Res<FoodValues>,
+    mut commands: Commands,
+) {
+    for (ent, archetype) in &new_dead_query {
+        // a feeder that died gives its place back and doesn't resume the meal when revived
+        commands.entity(ent).remove::<Eating>();
+        let food = food_values.get(archetype.0);
+        if food > 0 {
+            commands.entity(ent).insert(Carcass { food, feeders: 0 });
+        }
+    }
+    for ent in &revived_query {
+        commands.entity(ent).remove::<(Carcass, Consumed)>();
+    }
+}
+
+#[allow(clippy::type_complexity)]
 pub fn carnivore_eating_sys(
-    mut dead_query: Query<(&Position, Entity), (With<Dead>, With<PlayerEntity>)>,
+    mut dead_query: Query<
+        (&Position, &mut Carcass, Entity, &Faction),
+        (With<Dead>, Without<Consumed>),
+    >,
     mut zombie_query: Query<
-        (&H1emuEntity, &Position, Entity),
-        (With<Carnivore>, With<Alive>, Without<Eating>, With<Hungry>),
+        (&H1emuEntity, &Position, Entity, &Faction),
+        (
+            With<Carnivore>,
+            With<Alive>,
+            Without<Eating>,
+            With<Hungry>,
+            Without<BehaviorTreeState>,
+        ),
     >,
+    carcass_config: Res<CarcassConfig>,
+    factions: Res<Factions>,
+    mut events: AiEventWriter,
     mut commands: Commands,
 ) {
-    for (dead_pos, dead_ent) in &mut dead_query {
-        for (h1emu_ent, zombie_pos, ent) in &mut zombie_query {
+    for (h1emu_ent, zombie_pos, ent, faction) in &mut zombie_query {
+        for (dead_pos, mut carcass, dead_ent, dead_faction) in &mut dead_query {
+            if carcass.feeders >= carcass_config.feeding_slots
+                || !factions.can_eat(faction.0, dead_faction.0)
+            {
+                continue;
+            }
             if is_pos_in_radius(1.5, dead_pos, zombie_pos) {
                 let args = js_sys::Array::new();
                 args.push(&JsValue::from_str("Eating"));
                 h1emu_ent.play_animation(&args);
 
+                carcass.feeders += 1;
                 let current_time = Utc::now().timestamp_millis();
-                commands.entity(ent).insert(Eating { time: current_time });
+                commands.entity(ent).insert(Eating {
+                    time: current_time,
+                    corpse: dead_ent,
+                });
+                events.push(AiEventKind::EatingStarted, ent, Some(dead_ent));
+                break;
             }
         }
     }
 }
 
 pub fn finish_eating_sys(
-    mut query: Query<(&H1emuEntity, Entity, &Eating, &mut HungerLevel), With<Alive>>,
+    mut query: Query<(&H1emuEntity, Entity, &Eating, &mut HungerLevel, &Position), With<Alive>>,
+    mut carcass_query: Query<(&mut Carcass, &H1emuEntity, Has<PlayerEntity>)>,
+    carcass_config: Res<CarcassConfig>,
+    mut events: AiEventWriter,
     mut commands: Commands,
 ) {
     let current_time = Utc::now().timestamp_millis();
-    for (h1emu_ent, ent, eating, mut hunger_level) in &mut query {
+    for (h1emu_ent, ent, eating, mut hunger_level, pos) in &mut query {
         if eating.time + 10000_i64 <= current_time {
             log!("finish eating");
             let args = js_sys::Array::new();
             args.push(&JsValue::from_str("EatingDone"));
             h1emu_ent.play_animation(&args);
             commands.entity(ent).remove::<Eating>();
-            hunger_level.0 = 100;
+            commands.entity(ent).insert(LastMeal(*pos));
+            events.push(AiEventKind::EatingFinished, ent, Some(eating.corpse));
+            let Ok((mut carcass, corpse_h1emu_ent, is_player)) =
+                carcass_query.get_mut(eating.corpse)
+            else {
+                continue;
+            };
+            let bite = carcass.food.min(100 - hunger_level.0.min(100));
+            carcass.food -= bite;
+            hunger_level.0 += bite;
+            // only the bite that empties it, other feeders finishing this tick get nothing
+            if bite > 0 && carcass.food == 0 {
+                // players respawn so only other archetypes get removed
+                if carcass_config.despawn_consumed && !is_player {
+                    corpse_h1emu_ent.destroy();
+                    commands.entity(eating.corpse).despawn();
+                    events.push(AiEventKind::Despawned, eating.corpse, None);
+                } else {
+                    commands.entity(eating.corpse).insert(Consumed());
+                }
+            }
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn interrupt_eating_sys(
+    query: Query<
+        (
+            &H1emuEntity,
+            &Position,
+            Entity,
+            &Eating,
+            Option<&Damaged>,
+            Has<Hungry>,
+        ),
+        With<Alive>,
+    >,
+    player_query: Query<&Position, (With<PlayerEntity>, With<Alive>)>,
+    carcass_query: Query<(&Carcass, Has<Alive>)>,
+    hunger_config: Res<HungerConfig>,
+    mut commands: Commands,
+) {
+    for (h1emu_ent, pos, ent, eating, damaged, hungry) in &query {
+        let aggro_radius = hunger_config.aggro_radius(hungry);
+        let attacked =
+            damaged.is_some_and(|damaged| damaged.amount > 0.0 && damaged.time >= eating.time);
+        let disturbed = attacked
+            || player_query
+                .iter()
+                .any(|player_pos| is_pos_in_radius(aggro_radius, player_pos, pos));
+        let corpse = carcass_query.get(eating.corpse);
+        // an emptied carcass leaves nothing to wait for
+        let corpse_gone = match &corpse {
+            Ok((carcass, revived)) => *revived || carcass.food == 0,
+            Err(_) => true,
+        };
+        if !disturbed && !corpse_gone {
+            continue;
+        }
+        log!("eating interrupted");
+        let args = js_sys::Array::new();
+        args.push(&JsValue::from_str("EatingDone"));
+        h1emu_ent.play_animation(&args);
+        commands.entity(ent).remove::<Eating>();
+    }
+}
+
+/// Walk to the last known position, a chase takes over as soon as a target is in range
+#[allow(clippy::type_complexity)]
+pub fn investigate_sys(
+    mut query: Query<
+        (
+            Entity,
+            &H1emuEntity,
+            &Position,
+            &mut Investigating,
+            Has<Chasing>,
+        ),
+        (With<Alive>, Without<BehaviorTreeState>),
+    >,
+    mut commands: Commands,
+) {
+    let current_time = Utc::now().timestamp_millis();
+    for (ent, h1emu_ent, pos, mut investigating, chasing) in &mut query {
+        if chasing
+            || investigating.until <= current_time
+            || distance_2d(pos, &investigating.position) <= ATTACK_RANGE
+        {
+            commands.entity(ent).remove::<Investigating>();
+            continue;
+        }
+        if !investigating.ordered {
+            h1emu_ent.go_to(&go_to_args(&investigating.position));
+            investigating.ordered = true;
         }
     }
 }
 
-pub fn hungry_sys(mut query: Query<(Entity, &HungerLevel), With<Alive>>, mut commands: Commands) {
+pub fn hungry_sys(
+    mut query: Query<(Entity, &HungerLevel), With<Alive>>,
+    hunger_config: Res<HungerConfig>,
+    mut commands: Commands,
+) {
     for (ent, hunger_level) in &mut query {
-        if hunger_level.0 < 10 {
+        if hunger_level.0 < hunger_config.hungry_threshold {
             commands.entity(ent).insert(Hungry());
         }
     }
 }
+#[allow(clippy::type_complexity)]
 pub fn remove_hungry_sys(
     mut query: Query<(Entity, &HungerLevel), (With<Alive>, With<Hungry>)>,
+    hunger_config: Res<HungerConfig>,
     mut commands: Commands,
 ) {
     for (ent, hunger_level) in &mut query {
-        if hunger_level.0 > 50 {
+        if hunger_level.0 > hunger_config.sated_threshold {
             commands.entity(ent).remove::<Hungry>();
         }
     }
@@ -190,14 +488,70 @@ pub fn remove_hungry_sys(
 pub fn hunger_sys(
     mut query: Query<&mut HungerLevel, With<Alive>>,
     mut hunger_timer: ResMut<HungerTimer>,
+    hunger_config: Res<HungerConfig>,
 ) {
     let current_time = Utc::now().timestamp_millis();
     if hunger_timer.0 <= current_time {
         for mut hunger_level in &mut query {
-            if hunger_level.0 > 0 {
-                hunger_level.0 -= 1;
+            hunger_level.0 = hunger_level.0.saturating_sub(hunger_config.decay_amount);
+        }
+        hunger_timer.0 = current_time + hunger_config.decay_interval;
+    }
+}
+/// Starving carnivores search wider for food, well fed ones stay around their last meal,
+/// bears never roam out of their territory
+#[allow(clippy::type_complexity)]
+pub fn roam_sys(
+    mut query: Query<
+        (
+            &H1emuEntity,
+            &Position,
+            &HungerLevel,
+            &mut Roaming,
+            Option<&LastMeal>,
+            Option<&Territory>,
+        ),
+        (
+            With<Alive>,
+            Without<IsAttacking>,
+            Without<Eating>,
+            Without<Chasing>,
+            Without<Investigating>,
+            Without<Hunting>,
+            Without<Fleeing>,
+            Without<Flanking>,
+            Without<Retreating>,
+            Without<Charging>,
+            Without<Returning>,
+            Without<BehaviorTreeState>,
+        ),
+    >,
+    hunger_config: Res<HungerConfig>,
+) {
+    let current_time = Utc::now().timestamp_millis();
+    for (h1emu_ent, pos, hunger_level, mut roaming, last_meal, territory) in &mut query {
+        if current_time < roaming.next_move {
+            continue;
+        }
+        roaming.next_move = current_time + hunger_config.roam_interval;
+        let (center, radius) = match last_meal {
+            _ if hunger_level.0 == 0 => (*pos, hunger_config.starving_roam_radius),
+            Some(last_meal) if hunger_level.0 > hunger_config.sated_threshold => {
+                (last_meal.0, hunger_config.linger_radius)
             }
+            _ => (*pos, hunger_config.roam_radius),
+        };
+        roaming.wandering = radius > 0.0;
+        if !roaming.wandering {
+            continue;
+        }
+        let mut destination = random_pos_around(&center, radius);
+        if let Some(territory) = territory
+            && distance_2d(&territory.home, &destination) > territory.radius
+        {
+            let angle = (destination.z - territory.home.z).atan2(destination.x - territory.home.x);
+            destination = pos_on_ring(&territory.home, angle, territory.radius);
         }
-        hunger_timer.0 = current_time + 10_000;
+        h1emu_ent.go_to(&go_to_args(&destination));
     }
 }